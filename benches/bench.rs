@@ -18,13 +18,71 @@ fn macroed() {
     trace_span!(2);
 }
 
+/// Cycles of an empty span measured with each way of reading the counter,
+/// independent of the features tsc-trace was built with.
+#[cfg(target_arch = "x86_64")]
+mod counters {
+    use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+
+    pub fn plain() -> u64 {
+        unsafe {
+            let start = _rdtsc();
+            _rdtsc() - start
+        }
+    }
+
+    pub fn lfence() -> u64 {
+        unsafe {
+            _mm_lfence();
+            let start = _rdtsc();
+            _mm_lfence();
+            _mm_lfence();
+            let stop = _rdtsc();
+            _mm_lfence();
+            stop - start
+        }
+    }
+
+    pub fn rdtscp() -> u64 {
+        unsafe {
+            let start = __rdtscp(&mut 0);
+            __rdtscp(&mut 0) - start
+        }
+    }
+}
+
+/// Prints the mean and standard deviation of empty span cycles for each way of reading the counter,
+/// lower variance makes spans shorter than ~100 cycles more trustworthy.
+#[cfg(target_arch = "x86_64")]
+fn counter_variance() {
+    let samples = 100_000;
+    for (name, measure) in [
+        ("rdtsc", counters::plain as fn() -> u64),
+        ("lfence + rdtsc", counters::lfence),
+        ("rdtscp", counters::rdtscp),
+    ] {
+        let cycles: Vec<f64> = (0..samples).map(|_| measure() as f64).collect();
+        let mean = cycles.iter().sum::<f64>() / samples as f64;
+        let variance = cycles.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / samples as f64;
+        eprintln!("{name}: empty span mean {mean:.1} cycles, std dev {:.1}", variance.sqrt());
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
+    #[cfg(target_arch = "x86_64")]
+    counter_variance();
     let mut group = c.benchmark_group("tsc");
     let group = group
         .measurement_time(Duration::from_millis(1000))
         .warm_up_time(Duration::from_millis(1000));
-    group.bench_function("direct", |b| b.iter(|| black_box(direct())));
-    group.bench_function("macroed", |b| b.iter(|| black_box(macroed())));
+    group.bench_function("direct", |b| b.iter(direct));
+    group.bench_function("macroed", |b| b.iter(macroed));
+    #[cfg(target_arch = "x86_64")]
+    {
+        group.bench_function("rdtsc", |b| b.iter(counters::plain));
+        group.bench_function("lfence_rdtsc", |b| b.iter(counters::lfence));
+        group.bench_function("rdtscp", |b| b.iter(counters::rdtscp));
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,49 @@
+use crate::Trace;
+
+/// Cycles from the start of the previous span on the same thread to each span's start, in the order given,
+/// e.g. to see event rates and bursts rather than durations.
+///
+/// Previous means the latest earlier start, not the previous trace given, since read_traces returns nested spans
+/// in the order they stopped. The first span of each thread gets 0.
+pub fn inter_arrival(traces: &[Trace]) -> Vec<u64> {
+    let mut order: Vec<usize> = (0..traces.len()).collect();
+    order.sort_by_key(|&i| (traces[i].thread, traces[i].start));
+    let mut gaps = vec![0; traces.len()];
+    for pair in order.windows(2) {
+        let (previous, trace) = (&traces[pair[0]], &traces[pair[1]]);
+        if previous.thread == trace.thread {
+            gaps[pair[1]] = trace.start - previous.start;
+        }
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(thread: u64, start: u64, stop: u64) -> Trace {
+        Trace {
+            tag: 1,
+            start,
+            stop,
+            thread,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gaps_are_between_starts_per_thread() {
+        // a nested span stops, and so is given, before the one containing it
+        let traces = [
+            span(1, 20, 30),
+            span(1, 10, 40),
+            span(2, 15, 16),
+            span(1, 100, 110),
+            span(2, 18, 19),
+            span(1, 100, 105),
+        ];
+        assert_eq!(inter_arrival(&traces), vec![10, 0, 0, 80, 3, 0]);
+        assert!(inter_arrival(&[]).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{
+    default_capacity, RECORD_WIDTH_U64, TSC_TRACE_DROPPED, TSC_TRACE_INDEX, TSC_TRACE_LIMIT,
+    TSC_TRACE_SPANS,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// A thread's traces and the state describing them, while swapped out of the active array.
+struct Buffer {
+    spans: Vec<u64>,
+    index: usize,
+    limit: usize,
+    dropped: u64,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Buffer {
+            spans: Vec::with_capacity(default_capacity() * RECORD_WIDTH_U64),
+            index: 0,
+            limit: default_capacity() * RECORD_WIDTH_U64,
+            dropped: 0,
+        }
+    }
+
+    /// Exchanges this buffer with the thread's active array.
+    fn swap_active(&mut self) {
+        TSC_TRACE_SPANS.with(|spans| std::mem::swap(&mut self.spans, &mut spans.borrow_mut()));
+        self.index = TSC_TRACE_INDEX.with(|index| index.replace(self.index));
+        self.limit = TSC_TRACE_LIMIT.with(|limit| limit.replace(self.limit));
+        self.dropped = TSC_TRACE_DROPPED.with(|dropped| dropped.replace(self.dropped));
+    }
+}
+
+thread_local! {
+    /// buffers not currently active, None being the default buffer
+    static TSC_TRACE_BUFFERS: RefCell<HashMap<Option<String>, Buffer>> = RefCell::new(HashMap::new());
+    /// name of the buffer in the active array, None for the default buffer
+    static TSC_TRACE_ACTIVE_BUFFER: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Swaps the buffer `name` into the active array, returning the name of the one swapped out.
+fn activate(name: Option<String>) -> Option<String> {
+    let previous = TSC_TRACE_ACTIVE_BUFFER.with(|active| active.replace(name.clone()));
+    let mut buffer = TSC_TRACE_BUFFERS
+        .with(|buffers| buffers.borrow_mut().remove(&name))
+        .unwrap_or_else(Buffer::new);
+    buffer.swap_active();
+    TSC_TRACE_BUFFERS.with(|buffers| buffers.borrow_mut().insert(previous.clone(), buffer));
+    previous
+}
+
+/// Reactivates the previous buffer when dropped, even when the closure given to with_buffer panics.
+struct Restore(Option<Option<String>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            activate(previous);
+        }
+    }
+}
+
+/// Runs `f` with the current thread's traces going to the buffer `name` instead of the default one,
+/// e.g. to keep a thread's loading and serving phases apart.
+///
+/// Every function of this crate called within `f` uses that buffer, so it can be read, written or cleared
+/// independently with e.g. `with_buffer("serving", || write_traces_csv(&mut file))`.
+/// Buffers are created on first use with the default capacity, and calls can be nested.
+///
+/// Switching swaps the thread's array with the named one, so inserts outside with_buffer stay as fast as before.
+/// Not available with the `"const_array"` feature, whose array is too large to swap.
+pub fn with_buffer<R>(name: &str, f: impl FnOnce() -> R) -> R {
+    let name = Some(name.to_string());
+    if TSC_TRACE_ACTIVE_BUFFER.with(|active| *active.borrow() == name) {
+        return f();
+    }
+    let _restore = Restore(Some(activate(name)));
+    f()
+}
+
+/// Names of the buffers the current thread has used with with_buffer, sorted.
+pub fn buffer_names() -> Vec<String> {
+    let mut names: Vec<String> =
+        TSC_TRACE_BUFFERS.with(|buffers| buffers.borrow().keys().flatten().cloned().collect());
+    names.extend(TSC_TRACE_ACTIVE_BUFFER.with(|active| active.borrow().clone()));
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{current_index, read_traces, record_raw};
+
+    fn tags() -> Vec<u64> {
+        read_traces().iter().map(|t| t.tag).collect()
+    }
+
+    #[test]
+    fn traces_go_to_the_named_buffer_with_their_own_index() {
+        record_raw(1, 1, 2);
+        with_buffer("loading", || {
+            assert_eq!(current_index(), 0);
+            record_raw(2, 3, 4);
+            record_raw(3, 5, 6);
+            assert_eq!(tags(), vec![2, 3]);
+            with_buffer("serving", || record_raw(4, 7, 8));
+            assert_eq!(current_index(), 2);
+        });
+        assert_eq!(tags(), vec![1]);
+        assert_eq!(current_index(), 1);
+        assert_eq!(with_buffer("serving", tags), vec![4]);
+        assert_eq!(buffer_names(), ["loading", "serving"]);
+    }
+
+    #[test]
+    fn the_previous_buffer_is_restored_after_a_panic() {
+        record_raw(1, 1, 2);
+        let panicked = std::panic::catch_unwind(|| {
+            with_buffer("failing", || {
+                record_raw(2, 3, 4);
+                panic!("inside with_buffer");
+            })
+        });
+        assert!(panicked.is_err());
+        assert_eq!(tags(), vec![1]);
+        assert_eq!(with_buffer("failing", tags), vec![2]);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::export::tag_name;
+use crate::{build_tree, SpanNode, Trace};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Result, Write};
+
+/// Writes a Graphviz DOT call graph of which tags call which, reconstructed with build_tree.
+///
+/// Nodes are tags, labelled with their name from `names` (or the tag number), total cycles and self cycles,
+/// those spent in the tag's spans outside of their children.
+/// Edges go from parent to child tag, labelled with the number of calls and the child's total cycles,
+/// and drawn thicker the more cycles they account for.
+/// Traces from different threads (see merge_threaded) are only nested within their own thread.
+pub fn write_callgraph_dot(
+    writer: &mut impl Write,
+    traces: &[Trace],
+    names: &HashMap<u64, String>,
+) -> Result<()> {
+    // tag to (total cycles, self cycles), and (parent tag, child tag) to (calls, cycles)
+    let mut nodes: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    let mut edges: BTreeMap<(u64, u64), (u64, u64)> = BTreeMap::new();
+    let mut threads: BTreeMap<u64, Vec<Trace>> = BTreeMap::new();
+    for trace in traces {
+        threads.entry(trace.thread).or_default().push(*trace);
+    }
+    for traces in threads.values() {
+        // walked with an explicit stack, as nesting can be arbitrarily deep
+        let tree = build_tree(traces);
+        let mut stack: Vec<&SpanNode> = tree.roots.iter().collect();
+        while let Some(node) = stack.pop() {
+            let cycles = nodes.entry(node.trace.tag).or_default();
+            cycles.0 += node.total_cycles;
+            cycles.1 += node.self_cycles;
+            for child in &node.children {
+                let edge = edges.entry((node.trace.tag, child.trace.tag)).or_default();
+                edge.0 += 1;
+                edge.1 += child.total_cycles;
+                stack.push(child);
+            }
+        }
+    }
+
+    let name = |tag: u64| {
+        names
+            .get(&tag)
+            .cloned()
+            .unwrap_or_else(|| tag_name(tag))
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    };
+    writeln!(writer, "digraph tsc_trace {{")?;
+    writeln!(writer, "    node [shape=box];")?;
+    for (tag, (cycles, self_cycles)) in &nodes {
+        writeln!(writer, "    t{tag} [label=\"{}\\n{cycles} cycles\\n{self_cycles} self\"];", name(*tag))?;
+    }
+    let max_cycles = edges.values().map(|e| e.1).max().unwrap_or(0).max(1);
+    for ((parent, child), (calls, cycles)) in &edges {
+        let width = 1.0 + 4.0 * *cycles as f64 / max_cycles as f64;
+        writeln!(
+            writer,
+            "    t{parent} -> t{child} [label=\"{calls} calls\\n{cycles} cycles\", penwidth={width:.1}];"
+        )?;
+    }
+    writeln!(writer, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(tag: u64, start: u64, stop: u64) -> Trace {
+        Trace {
+            tag,
+            start,
+            stop,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_calls_and_total_and_self_cycles() {
+        // main calls parse twice and emit once, the first parse calls lex
+        let traces = [
+            span(3, 15, 25),
+            span(2, 10, 40),
+            span(2, 50, 70),
+            span(4, 80, 90),
+            span(1, 0, 100),
+        ];
+        let names = HashMap::from([(1, "main".to_string()), (2, "parse".to_string()), (3, "lex".to_string())]);
+        let mut out = vec![];
+        write_callgraph_dot(&mut out, &traces, &names).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = dot.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "digraph tsc_trace {",
+                "    node [shape=box];",
+                "    t1 [label=\"main\\n100 cycles\\n40 self\"];",
+                "    t2 [label=\"parse\\n50 cycles\\n40 self\"];",
+                "    t3 [label=\"lex\\n10 cycles\\n10 self\"];",
+                "    t4 [label=\"4\\n10 cycles\\n10 self\"];",
+                "    t1 -> t2 [label=\"2 calls\\n50 cycles\", penwidth=5.0];",
+                "    t1 -> t4 [label=\"1 calls\\n10 cycles\", penwidth=1.8];",
+                "    t2 -> t3 [label=\"1 calls\\n10 cycles\", penwidth=1.8];",
+                "}",
+            ]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use crate::rdtsc;
+#[cfg(all(target_arch = "aarch64", not(feature = "instant_clock")))]
+use std::arch::asm;
+use std::time::{Duration, Instant};
+
+/// What the values returned by rdtsc count. Values of different kinds are in different units,
+/// so traces recorded with them can't be compared without converting to time first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CounterKind {
+    /// x86 timestamp counter cycles
+    TscCycles,
+    /// aarch64 virtual timer ticks from cntvct_el0, usually much slower than the cpu clock
+    ArmVirtualTimer,
+    /// nanoseconds from Instant, with the `"instant_clock"` feature
+    Fallback,
+}
+
+impl CounterKind {
+    /// Name written for this kind in trace headers.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CounterKind::TscCycles => "tsc_cycles",
+            CounterKind::ArmVirtualTimer => "arm_virtual_timer",
+            CounterKind::Fallback => "fallback",
+        }
+    }
+
+    /// Inverse of as_str.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tsc_cycles" => Some(CounterKind::TscCycles),
+            "arm_virtual_timer" => Some(CounterKind::ArmVirtualTimer),
+            "fallback" => Some(CounterKind::Fallback),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of counter read by rdtsc in this build.
+pub const fn counter_kind() -> CounterKind {
+    if cfg!(feature = "instant_clock") {
+        CounterKind::Fallback
+    } else if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+        CounterKind::TscCycles
+    } else if cfg!(target_arch = "aarch64") {
+        CounterKind::ArmVirtualTimer
+    } else {
+        CounterKind::Fallback
+    }
+}
+
+/// Frequency of the counter read by rdtsc in Hz, when it is known without calibrating.
+/// That is the case for the aarch64 virtual timer, the `"instant_clock"` feature,
+/// and x86 cpus advertising their TSC frequency, see tsc_base_frequency. Use calibrate_hz otherwise.
+pub fn counter_hz() -> Option<u64> {
+    #[cfg(feature = "instant_clock")]
+    return Some(1_000_000_000);
+    #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(feature = "instant_clock")))]
+    return tsc_base_frequency();
+    #[cfg(all(target_arch = "aarch64", not(feature = "instant_clock")))]
+    {
+        let r: u64;
+        unsafe {
+            asm!("mrs {}, cntfrq_el0", out(reg) r);
+        }
+        Some(r)
+    }
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64", feature = "instant_clock")))]
+    None
+}
+
+/// Frequency of the x86 timestamp counter in Hz as advertised by the cpu, from CPUID leaf 0x15
+/// (crystal clock and TSC ratio) or else leaf 0x16 (base frequency). None on other targets, with the `"instant_clock"` feature,
+/// or on cpus that don't report it, such as most AMD cpus, use calibrate_hz there.
+///
+/// On cpus with an invariant TSC the counter runs at this base frequency however cores boost or throttle,
+/// so cycles are a measure of time rather than of work done, and converting them with cycles_to_nanos
+/// should use this rate. A calibration can be off if it happens to run while the frequency is changing.
+pub fn tsc_base_frequency() -> Option<u64> {
+    #[cfg(all(target_arch = "x86", not(feature = "instant_clock")))]
+    use core::arch::x86::__cpuid;
+    #[cfg(all(target_arch = "x86_64", not(feature = "instant_clock")))]
+    use core::arch::x86_64::__cpuid;
+    #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(feature = "instant_clock")))]
+    #[allow(unused_unsafe)]
+    unsafe {
+        let max_leaf = __cpuid(0).eax;
+        if max_leaf >= 0x15 {
+            let tsc = __cpuid(0x15);
+            if tsc.eax != 0 && tsc.ebx != 0 && tsc.ecx != 0 {
+                return Some(u64::from(tsc.ecx) * u64::from(tsc.ebx) / u64::from(tsc.eax));
+            }
+        }
+        if max_leaf >= 0x16 {
+            let base_mhz = __cpuid(0x16).eax & 0xffff;
+            if base_mhz != 0 {
+                return Some(u64::from(base_mhz) * 1_000_000);
+            }
+        }
+        None
+    }
+    #[cfg(not(all(any(target_arch = "x86", target_arch = "x86_64"), not(feature = "instant_clock"))))]
+    None
+}
+
+/// Current frequency in Hz of the core the calling thread last ran on, as reported by `/proc/thread-self/stat`,
+/// from Linux cpufreq's `scaling_cur_freq`, e.g. to note a run happened at 2.1GHz rather than the base frequency when cycles vary between runs.
+/// Cores change frequency constantly, so this is a snapshot. None on other systems, or where cpufreq isn't exposed,
+/// as in many virtual machines and containers.
+pub fn cpu_frequency_hz() -> Option<u64> {
+    // the processor field, 39th in the calling thread's stat, counting from the fields after the parenthesized command.
+    // /proc/self/stat would give the main thread's instead
+    let stat = std::fs::read_to_string("/proc/thread-self/stat").ok()?;
+    let cpu: usize = stat.rsplit_once(')')?.1.split_whitespace().nth(36)?.parse().ok()?;
+    let khz = std::fs::read_to_string(format!("/sys/devices/system/cpu/cpu{cpu}/cpufreq/scaling_cur_freq")).ok()?;
+    khz.trim().parse::<u64>().ok().map(|khz| khz * 1000)
+}
+
+/// Estimates the frequency of the counter read by rdtsc, in Hz,
+/// by comparing it against the system clock over `duration`.
+/// Longer durations give a more accurate estimate, 100ms is usually plenty.
+pub fn calibrate_hz(duration: Duration) -> u64 {
+    let instant = Instant::now();
+    let start = rdtsc();
+    std::thread::sleep(duration);
+    let stop = rdtsc();
+    let nanos = instant.elapsed().as_nanos().max(1);
+    (u128::from(stop.saturating_sub(start)) * 1_000_000_000 / nanos) as u64
+}
+
+/// Converts a number of counter cycles to nanoseconds, given the counter frequency from calibrate_hz.
+pub fn cycles_to_nanos(cycles: u64, tsc_hz: u64) -> u64 {
+    (u128::from(cycles) * 1_000_000_000 / u128::from(tsc_hz.max(1))) as u64
+}
+
+/// Converts nanoseconds to counter cycles, the inverse of cycles_to_nanos.
+pub fn nanos_to_cycles(nanos: u64, tsc_hz: u64) -> u64 {
+    (u128::from(nanos) * u128::from(tsc_hz) / 1_000_000_000) as u64
+}
+
+/// An Instant and rdtsc value read at the same moment, with the counter frequency,
+/// for converting between the two, see instant_to_tsc.
+#[derive(Clone, Copy, Debug)]
+pub struct ClockAnchor {
+    pub instant: Instant,
+    pub tsc: u64,
+    pub tsc_hz: u64,
+}
+
+impl ClockAnchor {
+    /// Anchors now, using counter_hz if known and otherwise calibrate_hz over 100ms.
+    pub fn now() -> Self {
+        let tsc_hz = counter_hz().unwrap_or_else(|| calibrate_hz(Duration::from_millis(100)));
+        ClockAnchor {
+            instant: Instant::now(),
+            tsc: rdtsc(),
+            tsc_hz,
+        }
+    }
+
+    pub fn instant_to_tsc(&self, instant: Instant) -> u64 {
+        if instant >= self.instant {
+            let nanos = instant.duration_since(self.instant).as_nanos() as u64;
+            self.tsc.saturating_add(nanos_to_cycles(nanos, self.tsc_hz))
+        } else {
+            let nanos = self.instant.duration_since(instant).as_nanos() as u64;
+            self.tsc.saturating_sub(nanos_to_cycles(nanos, self.tsc_hz))
+        }
+    }
+
+    pub fn tsc_to_instant(&self, tsc: u64) -> Instant {
+        if tsc >= self.tsc {
+            self.instant + Duration::from_nanos(cycles_to_nanos(tsc - self.tsc, self.tsc_hz))
+        } else {
+            // Instants can't go before an unspecified platform origin, clamp to the anchor rather than panic
+            self.instant
+                .checked_sub(Duration::from_nanos(cycles_to_nanos(self.tsc - tsc, self.tsc_hz)))
+                .unwrap_or(self.instant)
+        }
+    }
+}
+
+/// Process wide anchor used by instant_to_tsc and tsc_to_instant, created on first use,
+/// which may take 100ms to calibrate the counter frequency. Call it at startup to avoid that delay later.
+pub fn clock_anchor() -> &'static ClockAnchor {
+    static ANCHOR: std::sync::OnceLock<ClockAnchor> = std::sync::OnceLock::new();
+    ANCHOR.get_or_init(ClockAnchor::now)
+}
+
+/// Places an Instant from existing std timing on the rdtsc axis, to correlate it with traces.
+/// Accuracy depends on the calibrated frequency, and drifts slowly with distance from the anchor.
+pub fn instant_to_tsc(instant: Instant) -> u64 {
+    clock_anchor().instant_to_tsc(instant)
+}
+
+/// Converts an rdtsc value, such as a trace's start or stop, to an Instant. See instant_to_tsc.
+pub fn tsc_to_instant(tsc: u64) -> Instant {
+    clock_anchor().tsc_to_instant(tsc)
+}
+
+/// An rdtsc value and the CLOCK_MONOTONIC time in nanoseconds read at the same moment, to line traces up
+/// with `perf record` samples, whose timestamps use CLOCK_MONOTONIC by default on Linux.
+/// With the counter frequency, a perf time `t` is at about `tsc + nanos_to_cycles(t - ns, tsc_hz)` on the trace axis.
+///
+/// Linux only, other systems' monotonic clocks don't match perf's. The rdtsc value is the midpoint of counter reads on
+/// either side of clock_gettime, which is a vDSO call without a syscall on common configurations.
+/// Perf run with `-k` and another clock needs an anchor of that clock instead.
+#[cfg(target_os = "linux")]
+pub fn perf_clock_anchor() -> (u64, u64) {
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: std::ffi::c_long,
+        tv_nsec: std::ffi::c_long,
+    }
+    extern "C" {
+        fn clock_gettime(clock: std::ffi::c_int, time: *mut Timespec) -> std::ffi::c_int;
+    }
+    const CLOCK_MONOTONIC: std::ffi::c_int = 1;
+
+    let mut time = Timespec { tv_sec: 0, tv_nsec: 0 };
+    let before = rdtsc();
+    let result = unsafe { clock_gettime(CLOCK_MONOTONIC, &mut time) };
+    let after = rdtsc();
+    assert_eq!(result, 0, "clock_gettime(CLOCK_MONOTONIC) failed");
+    let ns = time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64;
+    (before + after.saturating_sub(before) / 2, ns)
+}
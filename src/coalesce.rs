@@ -0,0 +1,89 @@
+use crate::Trace;
+
+/// A run of consecutive spans of one tag merged by coalesce_traces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CoalescedSpan {
+    pub tag: u64,
+    /// start of the first span of the run
+    pub start: u64,
+    /// stop of the last span of the run
+    pub stop: u64,
+    /// number of spans in the run
+    pub count: u64,
+    /// sum of the spans' cycles, not counting the gaps between them
+    pub cycles: u64,
+}
+
+/// Merges consecutive spans of the same tag, in the order given, into one CoalescedSpan
+/// when each starts less than `gap_threshold` cycles after the previous one stopped,
+/// e.g. to turn thousands of iterations of a tight loop into one "repeated N times, M cycles total" record.
+///
+/// A span of another tag in between ends the run, so pass one tag's traces, or one nesting level, to merge across others.
+/// A threshold of 0 merges nothing.
+pub fn coalesce_traces(traces: &[Trace], gap_threshold: u64) -> Vec<CoalescedSpan> {
+    let mut coalesced: Vec<CoalescedSpan> = vec![];
+    for trace in traces {
+        match coalesced.last_mut() {
+            Some(last) if last.tag == trace.tag && trace.start.saturating_sub(last.stop) < gap_threshold => {
+                last.stop = last.stop.max(trace.stop);
+                last.count += 1;
+                last.cycles += trace.cycles();
+            }
+            _ => coalesced.push(CoalescedSpan {
+                tag: trace.tag,
+                start: trace.start,
+                stop: trace.stop,
+                count: 1,
+                cycles: trace.cycles(),
+            }),
+        }
+    }
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(tag: u64, start: u64, stop: u64) -> Trace {
+        Trace {
+            tag,
+            start,
+            stop,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merges_close_spans_of_one_tag() {
+        // adjacent, overlapping, then a gap of exactly the threshold
+        let traces = [span(1, 0, 10), span(1, 10, 20), span(1, 15, 25), span(1, 30, 40)];
+        assert_eq!(
+            coalesce_traces(&traces, 5),
+            vec![
+                CoalescedSpan {
+                    tag: 1,
+                    start: 0,
+                    stop: 25,
+                    count: 3,
+                    cycles: 30,
+                },
+                CoalescedSpan {
+                    tag: 1,
+                    start: 30,
+                    stop: 40,
+                    count: 1,
+                    cycles: 10,
+                },
+            ]
+        );
+        assert_eq!(coalesce_traces(&traces, 6).len(), 1);
+    }
+
+    #[test]
+    fn other_tags_and_a_zero_threshold_keep_spans_apart() {
+        let traces = [span(1, 0, 10), span(2, 10, 12), span(1, 12, 20)];
+        assert_eq!(coalesce_traces(&traces, 100).iter().map(|c| c.tag).collect::<Vec<_>>(), vec![1, 2, 1]);
+        assert_eq!(coalesce_traces(&[span(1, 0, 10), span(1, 10, 20)], 0).len(), 2);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::Trace;
+
+/// How many spans of `tag` are in progress over time, as `(timestamp, count)` steps ordered by timestamp,
+/// each count holding from its timestamp until the next step. The last step is always back to 0.
+///
+/// Spans are half open, so one ending exactly when another starts doesn't overlap it.
+/// With traces from several threads, e.g. merged with merge_threaded or before rebasing,
+/// this is how many threads were inside `tag` at once, as long as `tag` doesn't nest within itself.
+/// Peaks point at lock contention or thundering herds.
+pub fn concurrency_profile(traces: &[Trace], tag: u64) -> Vec<(u64, u32)> {
+    // ends sort before starts at the same timestamp
+    let mut events: Vec<(u64, bool)> = traces
+        .iter()
+        .filter(|t| t.tag == tag && t.stop > t.start)
+        .flat_map(|t| [(t.start, true), (t.stop, false)])
+        .collect();
+    events.sort_unstable();
+    let mut profile: Vec<(u64, u32)> = vec![];
+    let mut count: u32 = 0;
+    for (ts, is_start) in events {
+        if is_start {
+            count += 1;
+        } else {
+            count -= 1;
+        }
+        match profile.last_mut() {
+            Some(last) if last.0 == ts => last.1 = count,
+            _ => profile.push((ts, count)),
+        }
+    }
+    // drop steps that didn't change the count, such as a span ending as another starts
+    profile.dedup_by(|b, a| a.1 == b.1);
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(tag: u64, start: u64, stop: u64) -> Trace {
+        Trace {
+            tag,
+            start,
+            stop,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_overlaps_of_one_tag() {
+        let traces = [
+            span(1, 0, 10),
+            span(1, 5, 15),
+            span(2, 6, 7),
+            span(1, 15, 20),
+            span(1, 8, 9),
+        ];
+        assert_eq!(
+            concurrency_profile(&traces, 1),
+            vec![(0, 1), (5, 2), (8, 3), (9, 2), (10, 1), (20, 0)]
+        );
+    }
+
+    #[test]
+    fn empty_without_spans_of_tag() {
+        assert_eq!(concurrency_profile(&[span(2, 1, 2)], 1), vec![]);
+    }
+}
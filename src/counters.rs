@@ -0,0 +1,157 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// distinct tags each thread can count without falling back to the shared overflow map
+const SHARD_SLOTS: usize = 1024;
+
+/// One thread's counters. Only the owning thread writes, other threads only read when summing,
+/// so incrementing never contends and needs no atomic read-modify-write.
+struct Shard {
+    tags: Box<[AtomicU64]>,
+    counts: Box<[AtomicU64]>,
+    /// number of slots in use, published after a slot's tag is written
+    len: AtomicUsize,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Shard {
+            tags: (0..SHARD_SLOTS).map(|_| AtomicU64::new(0)).collect(),
+            counts: (0..SHARD_SLOTS).map(|_| AtomicU64::new(0)).collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Shards of every running thread that has counted. A thread's shard is removed when it exits,
+/// after folding its counts into OVERFLOW, so short lived threads don't accumulate.
+static SHARDS: Mutex<Vec<Arc<Shard>>> = Mutex::new(Vec::new());
+
+/// Counts for tags beyond a thread's SHARD_SLOTS, and of threads that have exited.
+static OVERFLOW: Mutex<Option<HashMap<u64, u64>>> = Mutex::new(None);
+
+struct LocalShard {
+    shard: Arc<Shard>,
+    /// slot of each tag this thread has counted
+    slots: HashMap<u64, usize>,
+}
+
+impl Drop for LocalShard {
+    fn drop(&mut self) {
+        // SHARDS is locked before OVERFLOW and held while folding, like counts() holds both while summing,
+        // so no count is missed or doubled
+        let mut shards = SHARDS.lock().unwrap();
+        let mut overflow = OVERFLOW.lock().unwrap();
+        let overflow = overflow.get_or_insert_with(HashMap::new);
+        for slot in 0..self.shard.len.load(Ordering::Acquire) {
+            *overflow.entry(self.shard.tags[slot].load(Ordering::Relaxed)).or_default() +=
+                self.shard.counts[slot].load(Ordering::Relaxed);
+        }
+        shards.retain(|shard| !Arc::ptr_eq(shard, &self.shard));
+    }
+}
+
+thread_local! {
+    static TSC_TRACE_COUNTERS: RefCell<Option<LocalShard>> = const { RefCell::new(None) };
+}
+
+/// Adds `n` to the counter for `tag`. Use the count! macro instead.
+///
+/// Counters are sharded per thread, so incrementing only touches the current thread's shard
+/// and never contends with other threads. counts() sums the shards.
+#[inline]
+pub fn count_tag(tag: u64, n: u64) {
+    TSC_TRACE_COUNTERS.with(|local| {
+        let mut local = local.borrow_mut();
+        let local = local.get_or_insert_with(|| {
+            let shard = Arc::new(Shard::new());
+            SHARDS.lock().unwrap().push(shard.clone());
+            LocalShard {
+                shard,
+                slots: HashMap::new(),
+            }
+        });
+        let slot = match local.slots.get(&tag) {
+            Some(&slot) => slot,
+            None => {
+                let slot = local.shard.len.load(Ordering::Relaxed);
+                if slot == SHARD_SLOTS {
+                    let mut overflow = OVERFLOW.lock().unwrap();
+                    *overflow.get_or_insert_with(HashMap::new).entry(tag).or_default() += n;
+                    return;
+                }
+                local.shard.tags[slot].store(tag, Ordering::Relaxed);
+                local.shard.len.store(slot + 1, Ordering::Release);
+                local.slots.insert(tag, slot);
+                slot
+            }
+        };
+        // this thread is the only writer, so a plain load and store is enough
+        let count = &local.shard.counts[slot];
+        count.store(count.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+    })
+}
+
+/// Totals of every tag counted with count!, summed across all threads.
+///
+/// Counts being incremented concurrently may or may not be included.
+pub fn counts() -> HashMap<u64, u64> {
+    let mut totals: HashMap<u64, u64> = HashMap::new();
+    // SHARDS stays locked while OVERFLOW is read, in the same order as LocalShard::drop,
+    // so a thread exiting meanwhile can't move its counts from a summed shard into OVERFLOW
+    let shards = SHARDS.lock().unwrap();
+    for shard in shards.iter() {
+        let len = shard.len.load(Ordering::Acquire);
+        for slot in 0..len {
+            *totals.entry(shard.tags[slot].load(Ordering::Relaxed)).or_default() +=
+                shard.counts[slot].load(Ordering::Relaxed);
+        }
+    }
+    if let Some(overflow) = OVERFLOW.lock().unwrap().as_ref() {
+        for (tag, n) in overflow {
+            *totals.entry(*tag).or_default() += n;
+        }
+    }
+    drop(shards);
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the tests spawn threads that add and remove shards, so they don't run concurrently
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn exited_threads_keep_counts_without_shards() {
+        let _serial = SERIAL.lock().unwrap();
+        let tag = 0xc0_0147;
+        let shards = SHARDS.lock().unwrap().len();
+        for _ in 0..4 {
+            std::thread::spawn(move || count_tag(tag, 5)).join().unwrap();
+        }
+        assert_eq!(SHARDS.lock().unwrap().len(), shards);
+        assert_eq!(counts()[&tag], 20);
+    }
+
+    #[test]
+    fn counts_never_double_exiting_threads() {
+        let _serial = SERIAL.lock().unwrap();
+        let tag = 0xc0_0148;
+        let threads = 200;
+        let spawner = std::thread::spawn(move || {
+            for _ in 0..threads {
+                std::thread::spawn(move || count_tag(tag, 1)).join().unwrap();
+            }
+        });
+        while !spawner.is_finished() {
+            let total = counts().get(&tag).copied().unwrap_or(0);
+            assert!(total <= threads, "{total} counted of at most {threads}");
+        }
+        spawner.join().unwrap();
+        assert_eq!(counts()[&tag], threads);
+    }
+}
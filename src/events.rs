@@ -0,0 +1,93 @@
+use crate::{Trace, EXIT_EVENT};
+use std::collections::HashMap;
+
+/// Pairs the enter and exit events recorded with the `"enter_exit"` feature back into one trace per span,
+/// matching each exit with the latest unmatched enter of its tag on its thread, so recursion pairs correctly.
+///
+/// Traces are returned in the order of their exits, like spans recorded without the feature.
+/// Unmatched events, e.g. spans still open or whose enter was overwritten when the array wrapped, are dropped.
+/// The exit's other fields, such as parent, are kept.
+///
+/// Only trace spans record events, so other traces, e.g. from insert_trace!, are kept as they are where they occur.
+/// Those are told apart from enter events by a stop differing from their start.
+pub fn pair_events(events: &[Trace]) -> Vec<Trace> {
+    let mut open: HashMap<(u64, u64), Vec<u64>> = HashMap::new();
+    let mut traces = vec![];
+    for event in events {
+        if event.tag & EXIT_EVENT == 0 {
+            if event.start == event.stop {
+                open.entry((event.thread, event.tag)).or_default().push(event.start);
+            } else {
+                traces.push(*event);
+            }
+            continue;
+        }
+        let tag = event.tag & !EXIT_EVENT;
+        if let Some(start) = open.get_mut(&(event.thread, tag)).and_then(Vec::pop) {
+            traces.push(Trace {
+                tag,
+                start,
+                stop: event.stop,
+                ..*event
+            });
+        }
+    }
+    traces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(thread: u64, tag: u64, at: u64) -> Trace {
+        Trace {
+            tag,
+            start: at,
+            stop: at,
+            thread,
+            ..Default::default()
+        }
+    }
+
+    fn spans(traces: &[Trace]) -> Vec<(u64, u64, u64)> {
+        traces.iter().map(|t| (t.tag, t.start, t.stop)).collect()
+    }
+
+    #[test]
+    fn pairs_recursion_innermost_first() {
+        let events = [
+            event(0, 1, 1),
+            event(0, 1, 2),
+            event(0, 2, 3),
+            event(0, 2 | EXIT_EVENT, 4),
+            event(0, 1 | EXIT_EVENT, 5),
+            event(0, 1 | EXIT_EVENT, 9),
+        ];
+        assert_eq!(spans(&pair_events(&events)), vec![(2, 3, 4), (1, 2, 5), (1, 1, 9)]);
+    }
+
+    #[test]
+    fn drops_unmatched_events() {
+        // an exit without enter, an enter left open, and an exit on another thread than its enter
+        let events = [
+            event(0, 2 | EXIT_EVENT, 1),
+            event(0, 3, 2),
+            event(0, 1, 3),
+            event(1, 1 | EXIT_EVENT, 4),
+            event(0, 1 | EXIT_EVENT, 5),
+        ];
+        assert_eq!(spans(&pair_events(&events)), vec![(1, 3, 5)]);
+    }
+
+    #[test]
+    fn keeps_traces_that_are_not_events() {
+        let inserted = Trace {
+            tag: 4,
+            start: 2,
+            stop: 6,
+            ..Default::default()
+        };
+        let events = [event(0, 1, 1), inserted, event(0, 1 | EXIT_EVENT, 8)];
+        assert_eq!(spans(&pair_events(&events)), vec![(4, 2, 6), (1, 1, 8)]);
+    }
+}
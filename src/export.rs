@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::io::{Result, Write};
+use std::sync::RwLock;
+
+/// Names given to tags with register_tag_name.
+static TAG_NAMES: RwLock<Option<HashMap<u64, String>>> = RwLock::new(None);
+
+/// Categories given to tags with set_tag_category.
+static TAG_CATEGORIES: RwLock<Option<HashMap<u64, String>>> = RwLock::new(None);
+
+/// Names `tag` in every named export format and in the header, as `tag_name.<tag>=<name>` entries,
+/// so the viewer picks names up from the dump rather than needing them repeated in its config.
+/// Registering a tag again replaces its name. Newlines in names are replaced with spaces.
+pub fn register_tag_name(tag: u64, name: &str) {
+    TAG_NAMES
+        .write()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(tag, name.replace(['\n', '\r'], " "));
+}
+
+/// Names registered with register_tag_name.
+pub fn tag_names() -> HashMap<u64, String> {
+    TAG_NAMES.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Puts `tag` in `category`, stored in the header as `tag_category.<tag>=<category>` entries,
+/// so the viewer groups and colors lanes of a category together without config.
+/// Setting a tag's category again replaces it. Newlines in categories are replaced with spaces.
+pub fn set_tag_category(tag: u64, category: &str) {
+    TAG_CATEGORIES
+        .write()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(tag, category.replace(['\n', '\r'], " "));
+}
+
+/// Categories set with set_tag_category.
+pub fn tag_categories() -> HashMap<u64, String> {
+    TAG_CATEGORIES.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Display name of a tag in named export formats, its registered name or else its number.
+pub(crate) fn tag_name(tag: u64) -> String {
+    TAG_NAMES
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|names| names.get(&tag).cloned())
+        .unwrap_or_else(|| tag.to_string())
+}
+
+/// Writes `s` as a quoted JSON string.
+pub(crate) fn write_json_string(writer: &mut impl Write, s: &str) -> Result<()> {
+    write!(writer, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(writer, "\\\"")?,
+            '\\' => write!(writer, "\\\\")?,
+            '\n' => write!(writer, "\\n")?,
+            '\r' => write!(writer, "\\r")?,
+            '\t' => write!(writer, "\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{c}")?,
+        }
+    }
+    write!(writer, "\"")
+}
+
+/// Nanoseconds as fractional microseconds, the unit of Chrome trace timestamps.
+pub(crate) fn micros(nanos: u64) -> String {
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
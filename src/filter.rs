@@ -0,0 +1,77 @@
+use crate::{tag_names, write_traces_csv, Trace};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::{Result, Write};
+
+type Keep = Box<dyn Fn(&Trace) -> bool>;
+
+thread_local! {
+    /// predicate of the innermost with_export_filter on this thread
+    static TSC_TRACE_EXPORT_FILTER: RefCell<Option<Keep>> = const { RefCell::new(None) };
+}
+
+/// Whether `trace` passes the current thread's export filter, true if there is none.
+pub(crate) fn keep(trace: &Trace) -> bool {
+    TSC_TRACE_EXPORT_FILTER.with(|filter| filter.borrow().as_ref().is_none_or(|keep| keep(trace)))
+}
+
+/// Whether an export filter is set on the current thread.
+pub(crate) fn filtering() -> bool {
+    TSC_TRACE_EXPORT_FILTER.with(|filter| filter.borrow().is_some())
+}
+
+/// Puts back the outer filter when dropped, even when the closure given to with_export_filter panics.
+struct Restore(Option<Keep>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        TSC_TRACE_EXPORT_FILTER.with(|filter| *filter.borrow_mut() = self.0.take());
+    }
+}
+
+/// Runs `f` with read_traces and every export of the current thread's traces skipping those `keep` returns false for,
+/// e.g. `with_export_filter(|t| t.tag == 7, || write_traces_binary(&mut file))`.
+///
+/// Traces are filtered while walking the array once, so no filtered copy is made.
+/// Calls can be nested, the innermost filter applies.
+pub fn with_export_filter<R>(keep: impl Fn(&Trace) -> bool + 'static, f: impl FnOnce() -> R) -> R {
+    let outer = TSC_TRACE_EXPORT_FILTER.with(|filter| filter.borrow_mut().replace(Box::new(keep)));
+    let _restore = Restore(outer);
+    f()
+}
+
+/// Writes only the current thread's traces whose tag is in `tags`, or whose name registered with register_tag_name
+/// is in `names`, as CSV, see write_traces_csv. Names are looked up once, before walking the array.
+pub fn write_traces_csv_filtered(writer: &mut impl Write, tags: &[u64], names: &[&str]) -> Result<()> {
+    let mut tags: HashSet<u64> = tags.iter().copied().collect();
+    tags.extend(tag_names().into_iter().filter(|(_, name)| names.contains(&name.as_str())).map(|(tag, _)| tag));
+    with_export_filter(move |trace| tags.contains(&trace.tag), || write_traces_csv(writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{_insert_trace, register_tag_name};
+
+    fn csv_tags(tags: &[u64], names: &[&str]) -> Vec<u64> {
+        let mut out = vec![];
+        write_traces_csv_filtered(&mut out, tags, names).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn filters_by_tag_and_by_registered_name() {
+        register_tag_name(0xf11_7e40, "filtered_by_name");
+        for tag in [1, 0xf11_7e40, 2, 3, 0xf11_7e40] {
+            _insert_trace(tag, 10, 20);
+        }
+        assert_eq!(csv_tags(&[], &["filtered_by_name"]), vec![0xf11_7e40, 0xf11_7e40]);
+        assert_eq!(csv_tags(&[2], &["filtered_by_name", "unregistered"]), vec![0xf11_7e40, 2, 0xf11_7e40]);
+        assert_eq!(csv_tags(&[3], &[]), vec![3]);
+    }
+}
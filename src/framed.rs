@@ -0,0 +1,126 @@
+use crate::{write_traces_binary, Trace, RECORD_WIDTH_U64};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// 32 bit FNV-1a hash of a frame's records.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0x811c_9dc5, |hash, b| (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193))
+}
+
+/// Writes the current thread's traces as one frame, so flushes appended to a file or a socket can be told apart.
+/// This is, in order:
+///
+/// count: u32, number of records in the frame
+/// checksum: u32, 32 bit FNV-1a of the records' bytes
+/// records: count records in the write_traces_binary format
+///
+/// The array isn't cleared, call clear_traces after each flush so the next frame only has new traces.
+/// Read frames back with read_traces_binary_framed.
+/// With the `"enter_exit"` feature the records are raw enter and exit events, pair them with pair_events.
+pub fn write_traces_binary_framed(writer: &mut impl Write) -> Result<()> {
+    let mut records = vec![];
+    write_traces_binary(&mut records)?;
+    let count = u32::try_from(records.len() / (RECORD_WIDTH_U64 * 8))
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "too many traces for one frame"))?;
+    writer.write_all(&count.to_le_bytes())?;
+    writer.write_all(&checksum(&records).to_le_bytes())?;
+    writer.write_all(&records)
+}
+
+/// Reads the next frame written by write_traces_binary_framed, None at the end of the stream.
+/// Fails with InvalidData if the frame's checksum doesn't match, and UnexpectedEof if it is truncated.
+/// Memory is only allocated for the bytes present, so a corrupt count can't exhaust it.
+pub fn read_traces_binary_framed(reader: &mut impl Read) -> Result<Option<Vec<Trace>>> {
+    let mut prefix = [0; 8];
+    match reader.read_exact(&mut prefix[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut prefix[1..])?;
+    let count = u64::from(u32::from_le_bytes(prefix[..4].try_into().unwrap()));
+    let expected = u32::from_le_bytes(prefix[4..].try_into().unwrap());
+    // the count isn't trusted until the checksum matches, so grow with the bytes actually read
+    // rather than allocating whatever a corrupt count asks for up front
+    let len = count * (RECORD_WIDTH_U64 * 8) as u64;
+    let mut records = vec![];
+    reader.take(len).read_to_end(&mut records)?;
+    if records.len() as u64 != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated trace frame"));
+    }
+    if checksum(&records) != expected {
+        return Err(Error::new(ErrorKind::InvalidData, "trace frame checksum mismatch"));
+    }
+    Ok(Some(
+        records
+            .chunks_exact(RECORD_WIDTH_U64 * 8)
+            .map(|bytes| Trace::from_record(&bytemuck::pod_read_unaligned::<[u64; RECORD_WIDTH_U64]>(bytes)))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clear_traces, record_raw};
+
+    /// Two frames, of traces tagged 1 and 2 then of a trace tagged 3.
+    fn two_frames() -> Vec<u8> {
+        let mut stream = vec![];
+        record_raw(1, 10, 20);
+        record_raw(2, 30, 40);
+        write_traces_binary_framed(&mut stream).unwrap();
+        clear_traces();
+        record_raw(3, 50, 60);
+        write_traces_binary_framed(&mut stream).unwrap();
+        stream
+    }
+
+    fn tags(frame: Vec<Trace>) -> Vec<u64> {
+        frame.iter().map(|t| t.tag).collect()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let stream = two_frames();
+        let mut reader = &stream[..];
+        assert_eq!(tags(read_traces_binary_framed(&mut reader).unwrap().unwrap()), vec![1, 2]);
+        let last = read_traces_binary_framed(&mut reader).unwrap().unwrap();
+        assert_eq!((last[0].tag, last[0].start, last[0].stop), (3, 50, 60));
+        assert!(read_traces_binary_framed(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_checksum_is_reported() {
+        let mut stream = two_frames();
+        // a bit flipped in the first frame's records
+        stream[8] ^= 1;
+        let err = read_traces_binary_framed(&mut &stream[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_last_frame_is_reported() {
+        let stream = two_frames();
+        for cut in [1, RECORD_WIDTH_U64 * 8, RECORD_WIDTH_U64 * 8 + 5] {
+            let mut reader = &stream[..stream.len() - cut];
+            assert!(read_traces_binary_framed(&mut reader).unwrap().is_some());
+            let err = read_traces_binary_framed(&mut reader).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "cut {cut} bytes");
+        }
+    }
+
+    #[test]
+    fn corrupt_count_is_truncated_not_allocated() {
+        let mut frame = u32::MAX.to_le_bytes().to_vec();
+        frame.extend_from_slice(&[0; 4 + 24]);
+        let err = read_traces_binary_framed(&mut &frame[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn empty_stream_has_no_frame() {
+        assert!(read_traces_binary_framed(&mut &[][..]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{counter_kind, dropped_count, has_wrapped, record_fields, tag_categories, tag_names, CounterKind, RECORD_WIDTH_U64};
+use std::collections::HashMap;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+use std::sync::Mutex;
+
+/// Version of the binary and CSV output formats.
+/// Bumped whenever the record layout or the meaning of a field changes,
+/// readers refuse to parse a version newer than the one they were built with.
+pub const TRACE_FORMAT_VERSION: u32 = 1;
+
+/// First 8 bytes of a binary dump that starts with a header.
+pub const TRACE_HEADER_MAGIC: [u8; 8] = *b"TSCTRACE";
+
+/// Process wide key/value pairs set by set_session_metadata, in the order first set.
+static SESSION_METADATA: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Stamps every dump written afterwards by this process with `key=value`, e.g. a git commit,
+/// benchmark name or host, in the binary header and as `# key=value` CSV comment lines.
+/// Setting a key again replaces its value.
+///
+/// Newlines are replaced with spaces and `=` in keys with `_`, as entries are stored one `key=value` per line.
+pub fn set_session_metadata(key: &str, value: &str) {
+    let key = key.replace(['\n', '\r'], " ").replace('=', "_");
+    let value = value.replace(['\n', '\r'], " ");
+    let mut metadata = SESSION_METADATA.lock().unwrap();
+    match metadata.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, v)) => *v = value,
+        None => metadata.push((key, value)),
+    }
+}
+
+/// Key/value pairs set with set_session_metadata.
+pub fn session_metadata() -> Vec<(String, String)> {
+    SESSION_METADATA.lock().unwrap().clone()
+}
+
+/// Header optionally written in front of binary traces by write_traces_header.
+///
+/// Layout, little-endian:
+///
+/// magic: 8 bytes, TRACE_HEADER_MAGIC
+/// version: u32
+/// entries_len: u32, number of bytes of entries that follow
+/// entries: utf-8 `key=value` lines, padded with newlines to a multiple of 8 bytes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub version: u32,
+    pub entries: Vec<(String, String)>,
+}
+
+impl TraceHeader {
+    /// Header describing output written by this build of the crate,
+    /// with the counter kind under `counter_kind` and its counter_hz under `counter_hz` if known, the record layout under `record_width_u64` and `record_fields`,
+    /// `events=enter_exit` with the `"enter_exit"` feature, as its records are events rather than spans,
+    /// the current thread's dropped_count under `dropped_traces` if its array has wrapped,
+    /// cpu_frequency_hz under `cpu_frequency_hz` with the `"cpu_frequency"` feature if known, the session metadata,
+    /// then registered tag names under `tag_name.<tag>` ordered by tag,
+    /// then tag categories under `tag_category.<tag>` ordered by tag.
+    pub fn current() -> Self {
+        let mut entries = vec![
+            ("counter_kind".to_string(), counter_kind().as_str().to_string()),
+        ];
+        if let Some(hz) = crate::counter_hz() {
+            entries.push(("counter_hz".to_string(), hz.to_string()));
+        }
+        entries.push(("record_width_u64".to_string(), RECORD_WIDTH_U64.to_string()));
+        entries.push(("record_fields".to_string(), record_fields().join(",")));
+        if cfg!(feature = "enter_exit") {
+            entries.push(("events".to_string(), "enter_exit".to_string()));
+        }
+        if has_wrapped() {
+            entries.push(("dropped_traces".to_string(), dropped_count().to_string()));
+        }
+        #[cfg(feature = "cpu_frequency")]
+        if let Some(hz) = crate::cpu_frequency_hz() {
+            entries.push(("cpu_frequency_hz".to_string(), hz.to_string()));
+        }
+        entries.extend(session_metadata());
+        let mut names: Vec<(u64, String)> = tag_names().into_iter().collect();
+        names.sort_unstable();
+        entries.extend(names.into_iter().map(|(tag, name)| (format!("tag_name.{tag}"), name)));
+        let mut categories: Vec<(u64, String)> = tag_categories().into_iter().collect();
+        categories.sort_unstable();
+        entries.extend(categories.into_iter().map(|(tag, category)| (format!("tag_category.{tag}"), category)));
+        TraceHeader {
+            version: TRACE_FORMAT_VERSION,
+            entries,
+        }
+    }
+
+    /// Like current, for output of traces read through read_traces, whose enter and exit events
+    /// have already been paired into spans, so without the `events` entry.
+    pub(crate) fn current_paired() -> Self {
+        let mut header = Self::current();
+        header.entries.retain(|(key, _)| key != "events");
+        header
+    }
+
+    /// Tag names registered with register_tag_name by the program that wrote the traces.
+    pub fn tag_names(&self) -> HashMap<u64, String> {
+        self.entries
+            .iter()
+            .filter_map(|(key, name)| Some((key.strip_prefix("tag_name.")?.parse().ok()?, name.clone())))
+            .collect()
+    }
+
+    /// Tag categories set with set_tag_category by the program that wrote the traces.
+    pub fn tag_categories(&self) -> HashMap<u64, String> {
+        self.entries
+            .iter()
+            .filter_map(|(key, category)| Some((key.strip_prefix("tag_category.")?.parse().ok()?, category.clone())))
+            .collect()
+    }
+
+    /// Whether the records are enter and exit events written with the `"enter_exit"` feature,
+    /// to be paired into spans with pair_events rather than read as zero length spans.
+    pub fn enter_exit_events(&self) -> bool {
+        self.get("events") == Some("enter_exit")
+    }
+
+    /// Number of traces overwritten before the dump was written, because the array wrapped around, 0 if none were.
+    pub fn dropped_traces(&self) -> u64 {
+        self.get("dropped_traces").and_then(|n| n.parse().ok()).unwrap_or(0)
+    }
+
+    /// Frequency in Hz of the counter the traces were recorded with, when it was known without calibrating.
+    pub fn counter_hz(&self) -> Option<u64> {
+        self.get("counter_hz").and_then(|hz| hz.parse().ok())
+    }
+
+    /// Frequency of the writing thread's core when the header was written, with the `"cpu_frequency"` feature.
+    pub fn cpu_frequency_hz(&self) -> Option<u64> {
+        self.get("cpu_frequency_hz").and_then(|hz| hz.parse().ok())
+    }
+
+    /// Number of u64s per record in the binary traces that follow, None for headers written before it was recorded,
+    /// whose records are 3 u64s unless written with field adding features.
+    pub fn record_width_u64(&self) -> Option<usize> {
+        self.get("record_width_u64").and_then(|width| width.parse().ok())
+    }
+
+    /// Names of the fields of each record in the binary traces that follow, see record_fields.
+    pub fn record_fields(&self) -> Option<Vec<String>> {
+        self.get("record_fields").map(|fields| fields.split(',').map(str::to_string).collect())
+    }
+
+    /// Kind of counter the traces were recorded with, None for headers written before it was recorded.
+    /// Traces with different kinds are in different units and shouldn't be analyzed together.
+    pub fn counter_kind(&self) -> Option<CounterKind> {
+        self.get("counter_kind").and_then(CounterKind::parse)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        let mut entries = String::new();
+        for (key, value) in &self.entries {
+            entries.push_str(&format!("{key}={value}\n"));
+        }
+        // keep the records that follow 8 byte aligned
+        while !entries.len().is_multiple_of(8) {
+            entries.push('\n');
+        }
+        writer.write_all(&TRACE_HEADER_MAGIC)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&(entries.len() as u32).to_le_bytes())?;
+        writer.write_all(entries.as_bytes())
+    }
+
+    /// Reads a header if the reader starts with one, otherwise consumes nothing and returns None,
+    /// as binary traces written without a header start directly with the first record.
+    ///
+    /// Errors with InvalidData if the header's version is newer than TRACE_FORMAT_VERSION.
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Self>> {
+        if !reader.fill_buf()?.starts_with(&TRACE_HEADER_MAGIC) {
+            return Ok(None);
+        }
+        let mut fixed = [0; 16];
+        reader.read_exact(&mut fixed)?;
+        let version = u32::from_le_bytes(fixed[8..12].try_into().unwrap());
+        if version > TRACE_FORMAT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "trace format version {version} is newer than the supported version {TRACE_FORMAT_VERSION}, upgrade tsc-trace to read it"
+                ),
+            ));
+        }
+        let entries_len = u32::from_le_bytes(fixed[12..16].try_into().unwrap());
+        let mut entries = vec![0; entries_len as usize];
+        reader.read_exact(&mut entries)?;
+        let entries = String::from_utf8(entries).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Some(TraceHeader {
+            version,
+            entries: entries
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }))
+    }
+}
+
+/// Writes the header for this build's binary format, see TraceHeader.
+/// Write it before write_traces_binary to make a self describing dump,
+/// or leave it out for formats like Clickhouse RowBinary that expect only records.
+pub fn write_traces_header(writer: &mut impl Write) -> Result<()> {
+    TraceHeader::current().write(writer)
+}
+
+/// Like write_traces_header, before spans from read_traces rather than the records of write_traces_binary.
+pub(crate) fn write_paired_traces_header(writer: &mut impl Write) -> Result<()> {
+    TraceHeader::current_paired().write(writer)
+}
@@ -1,34 +1,96 @@
 #![doc = include_str!("../README.md")]
 
 use std::cell::{Cell, RefCell};
-use std::io::{Result, Write};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Result, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(all(target_arch = "aarch64", not(feature = "instant_clock")))]
 use std::arch::asm;
 
-/// capacity in number of traces per thread
+mod arrival;
+#[cfg(not(feature = "const_array"))]
+mod buffers;
+mod callgraph;
+mod clock;
+mod coalesce;
+mod concurrency;
+mod counters;
+mod events;
+mod export;
+mod filter;
+mod framed;
+mod header;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod ndjson;
+#[cfg(feature = "packed_events")]
+mod packed;
+mod panic;
+mod percentiles;
+mod report;
+#[cfg(feature = "signal_safe")]
+mod signal;
+mod stacks;
+mod traces;
+mod tree;
+mod validate;
+mod wallclock;
+pub use arrival::*;
+#[cfg(not(feature = "const_array"))]
+pub use buffers::*;
+pub use callgraph::*;
+pub use clock::*;
+pub use coalesce::*;
+pub use concurrency::*;
+pub use counters::*;
+pub use events::*;
+pub use export::*;
+pub use filter::*;
+pub use framed::*;
+pub use header::*;
+#[cfg(feature = "msgpack")]
+pub use msgpack::*;
+pub use ndjson::*;
+#[cfg(feature = "packed_events")]
+pub use packed::*;
+pub use panic::*;
+pub use percentiles::*;
+pub use report::*;
+#[cfg(feature = "signal_safe")]
+pub use signal::*;
+pub use stacks::*;
+pub use traces::*;
+pub use tree::*;
+pub use validate::*;
+pub use wallclock::*;
+
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(all(not(feature = "off"), feature = "capacity_1_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 1_000_000;
+const FEATURE_CAPACITY: usize = 1_000_000;
 
-/// capacity in number of traces per thread
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(all(not(feature = "off"), feature = "capacity_8_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 8_000_000;
+const FEATURE_CAPACITY: usize = 8_000_000;
 
-/// capacity in number of traces per thread
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(all(not(feature = "off"), feature = "capacity_16_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 16_000_000;
+const FEATURE_CAPACITY: usize = 16_000_000;
 
-/// capacity in number of traces per thread
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(all(not(feature = "off"), feature = "capacity_32_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 32_000_000;
+const FEATURE_CAPACITY: usize = 32_000_000;
 
-/// capacity in number of traces per thread
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(all(not(feature = "off"), feature = "capacity_64_million"))]
-pub const TSC_TRACE_CAPACITY: usize = 64_000_000;
+const FEATURE_CAPACITY: usize = 64_000_000;
 
-/// capacity in number of traces per thread
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(feature = "off")]
-pub const TSC_TRACE_CAPACITY: usize = 0;
+const FEATURE_CAPACITY: usize = 0;
 
-/// capacity in number of traces per thread
+/// capacity in number of traces per thread set by the capacity features
 #[cfg(all(
     not(feature = "off"),
     not(feature = "capacity_1_million"),
@@ -37,9 +99,89 @@ pub const TSC_TRACE_CAPACITY: usize = 0;
     not(feature = "capacity_32_million"),
     not(feature = "capacity_64_million")
 ))]
-pub const TSC_TRACE_CAPACITY: usize = 1_000_000;
+const FEATURE_CAPACITY: usize = 1_000_000;
+
+/// capacity in number of traces per thread, from the `TSC_TRACE_BUILD_CAPACITY` environment variable at compile time
+/// if set, otherwise from the capacity features, 0 with the `"off"` feature
+pub const TSC_TRACE_CAPACITY: usize = match option_env!("TSC_TRACE_BUILD_CAPACITY") {
+    Some(capacity) if !cfg!(feature = "off") => parse_build_capacity(capacity),
+    _ => FEATURE_CAPACITY,
+};
+
+/// Parses `TSC_TRACE_BUILD_CAPACITY`, a number of traces that may contain `_` separators,
+/// failing the build if it isn't one.
+const fn parse_build_capacity(capacity: &str) -> usize {
+    let bytes = capacity.as_bytes();
+    let mut traces: usize = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => {}
+            digit @ b'0'..=b'9' => traces = traces * 10 + (digit - b'0') as usize,
+            _ => panic!("TSC_TRACE_BUILD_CAPACITY must be a number of traces"),
+        }
+        i += 1;
+    }
+    traces
+}
+
+/// Number of u64 fields stored per trace in this build: tag, start, stop, then any fields added by features,
+/// in the order of record_fields. Written to the header as `record_width_u64`, so readers of binary traces
+/// from builds with other features know the layout.
+pub const RECORD_WIDTH_U64: usize = 3
+    + cfg!(feature = "parent") as usize
+    + cfg!(feature = "aux") as usize
+    + cfg!(feature = "outcome") as usize
+    + cfg!(feature = "request") as usize
+    + cfg!(feature = "context") as usize;
+
+/// offset within a record of the parent span id
+#[cfg(feature = "parent")]
+const PARENT_FIELD: usize = 3;
+
+/// offset within a record of the auxiliary value
+#[cfg(feature = "aux")]
+const AUX_FIELD: usize = 3 + cfg!(feature = "parent") as usize;
+
+/// offset within a record of the outcome
+#[cfg(feature = "outcome")]
+const OUTCOME_FIELD: usize = 3 + cfg!(feature = "parent") as usize + cfg!(feature = "aux") as usize;
+
+/// offset within a record of the request id
+#[cfg(feature = "request")]
+const REQUEST_FIELD: usize =
+    3 + cfg!(feature = "parent") as usize + cfg!(feature = "aux") as usize + cfg!(feature = "outcome") as usize;
+
+/// offset within a record of the context value
+#[cfg(feature = "context")]
+const CONTEXT_FIELD: usize = 3
+    + cfg!(feature = "parent") as usize
+    + cfg!(feature = "aux") as usize
+    + cfg!(feature = "outcome") as usize
+    + cfg!(feature = "request") as usize;
 
-const CAPACITY: usize = TSC_TRACE_CAPACITY * 3;
+/// Set in the tag of the exit event recorded at the end of a span with the `"enter_exit"` feature, see pair_events.
+pub const EXIT_EVENT: u64 = 1 << 63;
+
+/// Trace::outcome of a span whose traced expression succeeded, see trace_span_result!
+pub const OUTCOME_OK: u64 = 1;
+
+/// Trace::outcome of a span whose traced expression failed, see trace_span_result!
+pub const OUTCOME_ERR: u64 = 2;
+
+#[cfg(feature = "const_array")]
+const CAPACITY: usize = TSC_TRACE_CAPACITY * RECORD_WIDTH_U64;
+
+/// Largest thread local const array allowed, in bytes. Bigger ones overflow the thread's stack
+/// or fail to initialize on most systems, so they are rejected at compile time instead.
+#[cfg(feature = "const_array")]
+const CONST_ARRAY_MAX_BYTES: usize = 256 << 20;
+
+#[cfg(feature = "const_array")]
+const _: () = assert!(
+    CAPACITY * std::mem::size_of::<u64>() <= CONST_ARRAY_MAX_BYTES,
+    "the \"const_array\" feature is limited to 256MB of traces per thread, use a smaller capacity_* feature or remove \"const_array\" to use the Vec backend"
+);
 
 #[cfg(feature = "const_array")]
 thread_local! {
@@ -49,33 +191,610 @@ thread_local! {
 
 #[cfg(not(feature = "const_array"))]
 thread_local! {
-    static TSC_TRACE_SPANS: RefCell<Vec<u64>> = RefCell::new(Vec::with_capacity(CAPACITY));
+    static TSC_TRACE_SPANS: RefCell<Vec<u64>> = RefCell::new(Vec::with_capacity(default_capacity() * RECORD_WIDTH_U64));
     static TSC_TRACE_INDEX: Cell<usize> = const { Cell::new(0) };
+    /// capacity of TSC_TRACE_SPANS in u64s, see init_capacity
+    static TSC_TRACE_LIMIT: Cell<usize> = Cell::new(default_capacity() * RECORD_WIDTH_U64);
 }
 
-/// Writes the current thread's array of traces in the format:
+/// Capacity in number of traces for threads that don't call init_capacity,
+/// from the `TSC_TRACE_CAPACITY` environment variable if set and parseable, otherwise TSC_TRACE_CAPACITY.
+#[cfg(not(feature = "const_array"))]
+fn default_capacity() -> usize {
+    env_capacity().unwrap_or(TSC_TRACE_CAPACITY)
+}
+
+/// Capacity in number of traces from the `TSC_TRACE_CAPACITY` environment variable, read once per process.
+/// Ignored when the `"off"` feature is enabled.
+#[cfg(not(feature = "const_array"))]
+fn env_capacity() -> Option<usize> {
+    static ENV_CAPACITY: std::sync::OnceLock<Option<usize>> = std::sync::OnceLock::new();
+    if cfg!(feature = "off") {
+        return None;
+    }
+    *ENV_CAPACITY.get_or_init(|| {
+        std::env::var("TSC_TRACE_CAPACITY")
+            .ok()
+            .and_then(|v| v.trim().replace('_', "").parse().ok())
+    })
+}
+
+/// Sets the current thread's capacity to `traces`, discarding any traces it has already recorded.
+/// Intended to be called at the start of a thread, before tracing.
 ///
-/// tag,start_rdtsc,stop_rdtsc,stop_minus_start\n
+/// The capacity used is, in order of precedence:
+/// the `TSC_TRACE_CAPACITY` environment variable if set to a number of traces,
+/// then `traces` as passed to this function,
+/// then TSC_TRACE_CAPACITY from the capacity features.
+/// A capacity of 0 records nothing on the thread.
 ///
-/// Stops writing once it encounters a stop_rdtsc of zero,
-/// assuming that's an unused portion of the array
-pub fn write_traces_csv(writer: &mut impl Write) -> Result<()> {
-    let mut res = Ok(());
+/// Not available with the `"const_array"` feature, whose capacity is fixed at compile time.
+#[cfg(not(feature = "const_array"))]
+pub fn init_capacity(traces: usize) {
+    let traces = if cfg!(feature = "off") {
+        0
+    } else {
+        env_capacity().unwrap_or(traces)
+    };
+    clear_traces();
+    TSC_TRACE_SPANS.with(|spans| {
+        *spans.borrow_mut() = Vec::with_capacity(traces * RECORD_WIDTH_U64);
+    });
+    TSC_TRACE_LIMIT.with(|limit| limit.set(traces * RECORD_WIDTH_U64));
+}
+
+/// Resizes the current thread's array to `traces`, keeping its recorded traces in order, and returns the previous capacity.
+/// When shrinking below the number recorded, only the most recent are kept and the rest count as dropped.
+/// Unlike init_capacity this can be called mid-run, e.g. to grow the array for a busier phase,
+/// and the `TSC_TRACE_CAPACITY` environment variable doesn't override it. A capacity of 0 stops recording on the thread.
+///
+/// Not available with the `"const_array"` feature, whose capacity is fixed at compile time.
+#[cfg(not(feature = "const_array"))]
+pub fn set_capacity_for_current_thread(traces: usize) -> usize {
+    let traces = if cfg!(feature = "off") { 0 } else { traces };
+    let previous = limit() / RECORD_WIDTH_U64;
+    let index = TSC_TRACE_INDEX.with(Cell::get);
+    TSC_TRACE_SPANS.with(|spans| {
+        let mut spans = spans.borrow_mut();
+        let (older, newer) = recorded(&spans[..], index);
+        let recorded = older.len() + newer.len();
+        let keep = recorded.min(traces * RECORD_WIDTH_U64);
+        let mut resized = Vec::with_capacity(traces * RECORD_WIDTH_U64);
+        let skip = recorded - keep;
+        if skip < older.len() {
+            resized.extend_from_slice(&older[skip..]);
+            resized.extend_from_slice(newer);
+        } else {
+            resized.extend_from_slice(&newer[skip - older.len()..]);
+        }
+        TSC_TRACE_DROPPED.with(|dropped| dropped.set(dropped.get() + (skip / RECORD_WIDTH_U64) as u64));
+        TSC_TRACE_INDEX.with(|index| index.set(resized.len()));
+        *spans = resized;
+    });
+    TSC_TRACE_LIMIT.with(|limit| limit.set(traces * RECORD_WIDTH_U64));
+    previous
+}
+
+thread_local! {
+    /// number of inserts to discard after each clear, see set_warmup
+    static TSC_TRACE_WARMUP: Cell<usize> = const { Cell::new(0) };
+    /// inserts still to be discarded
+    static TSC_TRACE_WARMUP_REMAINING: Cell<usize> = const { Cell::new(0) };
+    /// traces overwritten since the last clear, see dropped_count
+    static TSC_TRACE_DROPPED: Cell<u64> = const { Cell::new(0) };
+    /// spans started with trace_span_sampled!, to pick every Nth one
+    static TSC_TRACE_SAMPLED: Cell<u32> = const { Cell::new(0) };
+    /// id of the active request_scope
+    #[cfg(feature = "request")]
+    static TSC_TRACE_REQUEST: Cell<u64> = const { Cell::new(0) };
+    /// value given to set_context
+    #[cfg(feature = "context")]
+    static TSC_TRACE_CONTEXT: Cell<u64> = const { Cell::new(0) };
+    /// cycles spent inserting traces, see self_overhead_cycles
+    #[cfg(feature = "self_profile")]
+    static TSC_TRACE_SELF_OVERHEAD: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Capacity in number of traces of the current thread's array, after the environment variables,
+/// init_capacity and the capacity features have been applied. Assert on it in a startup test
+/// to check the capacity a binary ends up with when crates in its build enable different capacity features.
+pub fn configured_capacity() -> usize {
+    limit() / RECORD_WIDTH_U64
+}
+
+/// Highest rate current_sample_rate returns, once the array is full.
+pub const MAX_SAMPLE_RATE: u32 = 1 << 16;
+
+/// Capacity of the current thread's array, in u64s.
+#[inline(always)]
+fn limit() -> usize {
+    #[cfg(feature = "const_array")]
+    return CAPACITY;
+    #[cfg(not(feature = "const_array"))]
+    return TSC_TRACE_LIMIT.with(Cell::get);
+}
+
+/// Whether inserts have wrapped around the array and started overwriting,
+/// in which case the oldest trace is at the index.
+fn wrapped(spans: &[u64], index: usize) -> bool {
+    index < spans.len() && spans[index + 2] != 0
+}
+
+/// Names of the fields of a record in this build, in order, written to the header as `record_fields`.
+pub fn record_fields() -> Vec<&'static str> {
+    let mut fields = vec!["tag", "start", "stop"];
+    if cfg!(feature = "parent") {
+        fields.push("parent");
+    }
+    if cfg!(feature = "aux") {
+        fields.push("aux");
+    }
+    if cfg!(feature = "outcome") {
+        fields.push("outcome");
+    }
+    if cfg!(feature = "request") {
+        fields.push("request");
+    }
+    if cfg!(feature = "context") {
+        fields.push("context");
+    }
+    fields
+}
+
+/// The recorded portion of the array as (older, newer) slices, in the order traces were inserted.
+fn recorded(spans: &[u64], index: usize) -> (&[u64], &[u64]) {
+    if wrapped(spans, index) {
+        (&spans[index..], &spans[..index])
+    } else {
+        (&spans[..index.min(spans.len())], &[])
+    }
+}
+
+/// A single recorded span, as stored in the thread local array.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Trace {
+    pub tag: u64,
+    pub start: u64,
+    pub stop: u64,
+    /// start of the explicitly linked parent span, see span_with_parent.
+    /// Always 0 unless the `"parent"` feature is enabled.
+    pub parent: u64,
+    /// arbitrary value given to insert_trace_aux!, e.g. a sampled address to join with hardware sampling data.
+    /// Always 0 unless the `"aux"` feature is enabled.
+    pub aux: u64,
+    /// OUTCOME_OK or OUTCOME_ERR for spans recorded by trace_span_result!, 0 for other spans.
+    /// Always 0 unless the `"outcome"` feature is enabled.
+    pub outcome: u64,
+    /// id of the request_scope active on the thread when the trace was recorded, 0 outside of any.
+    /// Always 0 unless the `"request"` feature is enabled.
+    pub request: u64,
+    /// value given to set_context on the thread when the trace was recorded, 0 if none or after clear_context.
+    /// Always 0 unless the `"context"` feature is enabled.
+    pub context: u64,
+    /// thread the trace was recorded on, as an index into Traces::threads plus one.
+    /// 0 unless set by merge_threaded, or thread_number when copied by append_to.
+    /// It isn't stored in the thread local array or written to files.
+    pub thread: u64,
+}
+
+impl Trace {
+    /// stop minus start, saturating at zero for inverted spans
+    pub fn cycles(&self) -> u64 {
+        self.stop.saturating_sub(self.start)
+    }
+
+    /// Trace from a record whose fields are named by `fields`, as written by a build with other features.
+    /// Fields this Trace doesn't have are ignored.
+    fn from_named_record(fields: &[String], record: &[u64]) -> Self {
+        let mut trace = Trace::default();
+        for (name, value) in fields.iter().zip(record) {
+            match name.as_str() {
+                "tag" => trace.tag = *value,
+                "start" => trace.start = *value,
+                "stop" => trace.stop = *value,
+                "parent" => trace.parent = *value,
+                "aux" => trace.aux = *value,
+                "outcome" => trace.outcome = *value,
+                "request" => trace.request = *value,
+                "context" => trace.context = *value,
+                _ => {}
+            }
+        }
+        trace
+    }
+
+    // the update fills whichever fields the enabled features don't store
+    #[allow(clippy::needless_update)]
+    fn from_record(record: &[u64]) -> Self {
+        Trace {
+            tag: record[0],
+            start: record[1],
+            stop: record[2],
+            #[cfg(feature = "parent")]
+            parent: record[PARENT_FIELD],
+            #[cfg(feature = "aux")]
+            aux: record[AUX_FIELD],
+            #[cfg(feature = "outcome")]
+            outcome: record[OUTCOME_FIELD],
+            #[cfg(feature = "request")]
+            request: record[REQUEST_FIELD],
+            #[cfg(feature = "context")]
+            context: record[CONTEXT_FIELD],
+            ..Default::default()
+        }
+    }
+
+    fn to_record(self) -> [u64; RECORD_WIDTH_U64] {
+        #[allow(unused_mut)]
+        let mut record = record(self.tag, self.start, self.stop);
+        #[cfg(feature = "parent")]
+        {
+            record[PARENT_FIELD] = self.parent;
+        }
+        #[cfg(feature = "aux")]
+        {
+            record[AUX_FIELD] = self.aux;
+        }
+        #[cfg(feature = "outcome")]
+        {
+            record[OUTCOME_FIELD] = self.outcome;
+        }
+        #[cfg(feature = "request")]
+        {
+            record[REQUEST_FIELD] = self.request;
+        }
+        #[cfg(feature = "context")]
+        {
+            record[CONTEXT_FIELD] = self.context;
+        }
+        record
+    }
+}
+
+/// Copies the current thread's array of traces into a Vec, for analysis.
+/// Traces are in the order they were inserted, oldest first, even once the array has wrapped.
+///
+/// Stops once it encounters a stop_rdtsc of zero, like write_traces_csv.
+/// With the `"enter_exit"` feature the events are paired into spans with pair_events, ordered by their exits,
+/// and every export built on it does likewise, except write_traces_binary which keeps the raw events.
+pub fn read_traces() -> Vec<Trace> {
+    with_traces(|traces| traces.collect())
+}
+
+/// Calls `f` with an iterator over the current thread's traces in the order read_traces returns them,
+/// without copying the array unless events are paired. Traces can't be inserted on this thread until `f` returns.
+pub(crate) fn with_traces<R>(f: impl FnOnce(&mut dyn Iterator<Item = Trace>) -> R) -> R {
+    with_unfiltered_traces(|traces| f(&mut traces.filter(filter::keep)))
+}
+
+/// Like with_traces, ignoring any with_export_filter.
+///
+/// With the `"enter_exit"` feature the events are paired into spans with pair_events first,
+/// so every export sees spans, and filters apply to whole spans. Unmatched events, e.g. of spans still open, are left out.
+fn with_unfiltered_traces<R>(f: impl FnOnce(&mut dyn Iterator<Item = Trace>) -> R) -> R {
+    let index = TSC_TRACE_INDEX.with(Cell::get);
+    TSC_TRACE_SPANS.with(|spans| {
+        let spans = spans.borrow();
+        let (older, newer) = recorded(&spans[..], index);
+        let mut records = older
+            .chunks_exact(RECORD_WIDTH_U64)
+            .chain(newer.chunks_exact(RECORD_WIDTH_U64))
+            .map(Trace::from_record)
+            .take_while(|t| t.stop != 0);
+        if cfg!(feature = "enter_exit") {
+            f(&mut pair_events(&records.collect::<Vec<_>>()).into_iter())
+        } else {
+            f(&mut records)
+        }
+    })
+}
+
+/// Copies the `n` most recently recorded traces on the current thread, oldest first,
+/// without modifying the array, e.g. for a panic hook to show what the thread was last doing.
+///
+/// Returns fewer if fewer were recorded, and none if the array is borrowed because
+/// this was called while another function of this crate was running on the thread.
+pub fn peek_last(n: usize) -> Vec<Trace> {
+    let index = TSC_TRACE_INDEX.with(Cell::get);
+    TSC_TRACE_SPANS.with(|spans| {
+        let Ok(spans) = spans.try_borrow() else {
+            return vec![];
+        };
+        let (older, newer) = recorded(&spans[..], index);
+        let mut last: Vec<Trace> = newer
+            .rchunks_exact(RECORD_WIDTH_U64)
+            .chain(older.rchunks_exact(RECORD_WIDTH_U64))
+            .map(Trace::from_record)
+            .filter(|t| t.stop != 0)
+            .take(n)
+            .collect();
+        last.reverse();
+        last
+    })
+}
+
+/// Bytes used to store `spans` traces, both in a thread's array and in a write_traces_binary dump:
+/// 8 bytes for each of tag, start and stop, plus 8 for each of the `"parent"`, `"aux"`, `"outcome"`, `"request"` and `"context"` features enabled.
+///
+/// E.g. at 100,000 spans per second for 60 seconds without any of them, `estimate_bytes(6_000_000)` is 144MB.
+/// A header adds a few dozen bytes, CSV is usually 2 to 3 times larger.
+pub const fn estimate_bytes(spans: usize) -> usize {
+    spans * RECORD_WIDTH_U64 * std::mem::size_of::<u64>()
+}
+
+/// Number of traces currently held in the current thread's array, which is its capacity once it has wrapped.
+pub fn trace_count() -> usize {
+    let index = TSC_TRACE_INDEX.with(Cell::get);
     TSC_TRACE_SPANS.with(|spans| {
         let spans = spans.borrow();
-        for chunk in spans.chunks_exact(3) {
-            if let &[tag, start, stop] = chunk {
-                if stop == 0 {
+        let (older, newer) = recorded(&spans[..], index);
+        (older.len() + newer.len()) / RECORD_WIDTH_U64
+    })
+}
+
+/// Cycles between the earliest start and the latest stop of the current thread's traces,
+/// the time the recording covers, or 0 without traces.
+pub fn recording_span_cycles() -> u64 {
+    with_traces(|traces| {
+        let (start, stop) = traces.fold((u64::MAX, 0), |(start, stop), t| (start.min(t.start), stop.max(t.stop)));
+        stop.saturating_sub(start)
+    })
+}
+
+/// recording_span_cycles in nanoseconds, given the counter frequency `tsc_hz` from calibrate_hz.
+pub fn recording_span_ns(tsc_hz: u64) -> u64 {
+    cycles_to_nanos(recording_span_cycles(), tsc_hz)
+}
+
+/// Position in the current thread's array where the next trace will be written, in traces.
+/// Once the array has wrapped it is also the position of the oldest trace.
+pub fn current_index() -> usize {
+    let index = TSC_TRACE_INDEX.with(Cell::get);
+    // a full array wraps on the next insert
+    if index >= limit() {
+        0
+    } else {
+        index / RECORD_WIDTH_U64
+    }
+}
+
+/// Number of traces the current thread can insert before the array wraps and starts overwriting,
+/// 0 once it has wrapped. Flush and clear_traces before it reaches 0 to keep every trace.
+pub fn remaining_capacity() -> usize {
+    let index = TSC_TRACE_INDEX.with(Cell::get);
+    let wrapped = TSC_TRACE_SPANS.with(|spans| wrapped(&spans.borrow()[..], index));
+    if wrapped {
+        0
+    } else {
+        limit().saturating_sub(index) / RECORD_WIDTH_U64
+    }
+}
+
+/// Number of the current thread's traces that were overwritten when the array wrapped around, since the last clear.
+pub fn dropped_count() -> u64 {
+    TSC_TRACE_DROPPED.with(Cell::get)
+}
+
+/// Whether the current thread's array has wrapped around since the last clear, overwriting its oldest traces.
+/// Exports still write the remaining traces oldest first, and record dropped_count in the header as `dropped_traces`
+/// so a dump shows that its start is missing.
+pub fn has_wrapped() -> bool {
+    dropped_count() > 0
+}
+
+/// Cycles the current thread spent inserting traces since the last clear, with the `"self_profile"` feature, otherwise 0.
+///
+/// Each insert is timed from entry to after storing the trace, which includes about one counter read,
+/// so comparing it to the cycles of the outermost spans shows what share of a run tracing itself consumed.
+pub fn self_overhead_cycles() -> u64 {
+    #[cfg(feature = "self_profile")]
+    return TSC_TRACE_SELF_OVERHEAD.with(Cell::get);
+    #[cfg(not(feature = "self_profile"))]
+    0
+}
+
+/// Adds the cycles since its creation to self_overhead_cycles when dropped.
+#[cfg(feature = "self_profile")]
+struct SelfProfile(u64);
+
+#[cfg(feature = "self_profile")]
+impl Drop for SelfProfile {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let cycles = rdtsc().saturating_sub(self.0);
+        TSC_TRACE_SELF_OVERHEAD.with(|overhead| overhead.set(overhead.get() + cycles));
+    }
+}
+
+/// Removes the current thread's traces for which `keep` returns false,
+/// compacting the remaining ones in place in the order they were recorded.
+/// Does not allocate, so it's suitable for shrinking a huge buffer before writing it.
+pub fn prune_traces(keep: impl Fn(&Trace) -> bool) {
+    TSC_TRACE_INDEX.with(|index| {
+        TSC_TRACE_SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            let i = index.get();
+            // once the buffer has wrapped the oldest trace is at the index, move it to the front
+            if wrapped(&spans[..], i) {
+                spans.rotate_left(i);
+            }
+            let mut kept = 0;
+            for from in (0..spans.len()).step_by(RECORD_WIDTH_U64) {
+                let trace = Trace::from_record(&spans[from..from + RECORD_WIDTH_U64]);
+                if trace.stop == 0 {
                     break;
                 }
-                if let e @ Err(_) = writeln!(writer, "{tag},{start},{stop},{}", stop - start) {
-                    res = e;
-                    break;
+                if keep(&trace) {
+                    spans.copy_within(from..from + RECORD_WIDTH_U64, kept);
+                    kept += RECORD_WIDTH_U64;
                 }
             }
-        }
+            #[cfg(feature = "const_array")]
+            spans[kept..].fill(0);
+            #[cfg(not(feature = "const_array"))]
+            spans.truncate(kept);
+            index.set(kept);
+        })
+    })
+}
+
+/// Discards all of the current thread's traces, and restarts the warmup set by set_warmup.
+pub fn clear_traces() {
+    TSC_TRACE_INDEX.with(|index| index.set(0));
+    TSC_TRACE_SPANS.with(|spans| {
+        let mut spans = spans.borrow_mut();
+        #[cfg(feature = "const_array")]
+        spans.fill(0);
+        #[cfg(not(feature = "const_array"))]
+        spans.clear();
     });
-    res
+    TSC_TRACE_WARMUP_REMAINING.with(|remaining| remaining.set(TSC_TRACE_WARMUP.with(Cell::get)));
+    TSC_TRACE_DROPPED.with(|dropped| dropped.set(0));
+    #[cfg(feature = "self_profile")]
+    TSC_TRACE_SELF_OVERHEAD.with(|overhead| overhead.set(0));
+}
+
+/// Appends the current thread's traces to `sink`, oldest first with Trace::thread set to thread_number,
+/// then discards them like clear_traces, for a collector that owns its shared Vec instead of calling read_traces per thread.
+/// The lock is held only while the traces are copied into `sink`.
+/// Every trace is moved, including those an active with_export_filter rejects, so none are lost by the clear.
+pub fn append_to(sink: &Mutex<Vec<Trace>>) {
+    let thread = thread_number();
+    with_unfiltered_traces(|traces| sink.lock().unwrap().extend(traces.map(|trace| Trace { thread, ..trace })));
+    clear_traces();
+}
+
+/// Number of the current thread's ThreadId, which is unique for the life of the process and never 0.
+pub fn thread_number() -> u64 {
+    // ThreadId's only stable representation is its Debug output, ThreadId(N)
+    let id: String = format!("{:?}", std::thread::current().id()).chars().filter(char::is_ascii_digit).collect();
+    id.parse().unwrap_or(0)
+}
+
+/// Replaces the current thread's traces with `traces`, as if they had been recorded in that order,
+/// for deterministic fixtures when testing code that reads or exports traces.
+/// Warmup doesn't apply, and traces beyond the capacity wrap around as usual.
+#[cfg(feature = "testing")]
+pub fn inject_traces(traces: &[Trace]) {
+    clear_traces();
+    let warmup = TSC_TRACE_WARMUP_REMAINING.with(|remaining| remaining.replace(0));
+    for trace in traces {
+        insert_record(trace.to_record());
+    }
+    TSC_TRACE_WARMUP_REMAINING.with(|remaining| remaining.set(warmup));
+}
+
+/// Discards the first `n` traces inserted on the current thread after this call and after each clear_traces,
+/// so that cold cache outliers at the start of a run don't skew the recorded data.
+pub fn set_warmup(n: usize) {
+    TSC_TRACE_WARMUP.with(|warmup| warmup.set(n));
+    TSC_TRACE_WARMUP_REMAINING.with(|remaining| remaining.set(n));
+}
+
+/// Reads traces written by write_traces_binary, with or without a leading header.
+/// Skips zeroed / unused records. Enter and exit events from a build with the `"enter_exit"` feature,
+/// marked by the header's `events=enter_exit` entry, are paired into spans with pair_events.
+///
+/// Errors with InvalidData if the header's format version is newer than TRACE_FORMAT_VERSION.
+pub fn read_traces_binary(reader: &mut impl BufRead) -> Result<Vec<Trace>> {
+    let header = TraceHeader::read(reader)?;
+    let traces = match header.as_ref().and_then(TraceHeader::record_fields) {
+        Some(fields) if fields != record_fields() => read_named_records(reader, &fields)?,
+        _ => read_records(reader)?,
+    };
+    if header.as_ref().is_some_and(TraceHeader::enter_exit_events) {
+        return Ok(pair_events(&traces));
+    }
+    Ok(traces)
+}
+
+/// Reads records laid out as in this build until the end of `reader`.
+fn read_records(reader: &mut impl BufRead) -> Result<Vec<Trace>> {
+    let mut traces = vec![];
+    let mut bytes = [0; RECORD_WIDTH_U64 * 8];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let record: [u64; RECORD_WIDTH_U64] = bytemuck::pod_read_unaligned(&bytes);
+        let trace = Trace::from_record(&record);
+        if trace.stop != 0 {
+            traces.push(trace);
+        }
+    }
+    Ok(traces)
+}
+
+/// Reads records laid out as `fields` by a build with other features until the end of `reader`.
+fn read_named_records(reader: &mut impl BufRead, fields: &[String]) -> Result<Vec<Trace>> {
+    let mut traces = vec![];
+    let mut bytes = vec![0; fields.len().max(1) * 8];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let record: Vec<u64> = bytes.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).collect();
+        let trace = Trace::from_named_record(fields, &record);
+        if trace.stop != 0 {
+            traces.push(trace);
+        }
+    }
+    Ok(traces)
+}
+
+/// Writes the current thread's array of traces in the format:
+///
+/// tag,start_rdtsc,stop_rdtsc,stop_minus_start\n
+///
+/// preceded by a comment line with the format version, `# tsc_trace_format_version=1`
+/// and a `# key=value` comment line per entry of TraceHeader::current, such as the session metadata
+///
+/// With the `"parent"` feature each line has an additional ,parent column,
+/// then with the `"aux"` feature an additional ,aux column, then with the `"outcome"` feature an additional ,outcome column,
+/// then with the `"request"` feature an additional ,request column, then with the `"context"` feature an additional ,context column.
+///
+/// Traces are written in the order they were inserted, oldest first, even once the array has wrapped,
+/// skipping those rejected by with_export_filter if one is set.
+/// With the `"enter_exit"` feature the events are paired into spans first, like read_traces does.
+/// Stops writing once it encounters a stop_rdtsc of zero,
+/// assuming that's an unused portion of the array
+pub fn write_traces_csv(writer: &mut impl Write) -> Result<()> {
+    write_csv_header(writer)?;
+    with_traces(|traces| {
+        for trace in traces {
+            write_csv_line(writer, &trace)?;
+        }
+        Ok(())
+    })
+}
+
+/// Comment lines starting every CSV output, the format version then the header entries.
+pub(crate) fn write_csv_header(writer: &mut impl Write) -> Result<()> {
+    writeln!(writer, "# tsc_trace_format_version={TRACE_FORMAT_VERSION}")?;
+    for (key, value) in TraceHeader::current_paired().entries {
+        writeln!(writer, "# {key}={value}")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_csv_line(writer: &mut impl Write, trace: &Trace) -> Result<()> {
+    let Trace { tag, start, stop, .. } = trace;
+    write!(writer, "{tag},{start},{stop},{}", trace.cycles())?;
+    #[cfg(feature = "parent")]
+    write!(writer, ",{}", trace.parent)?;
+    #[cfg(feature = "aux")]
+    write!(writer, ",{}", trace.aux)?;
+    #[cfg(feature = "outcome")]
+    write!(writer, ",{}", trace.outcome)?;
+    #[cfg(feature = "request")]
+    write!(writer, ",{}", trace.request)?;
+    #[cfg(feature = "context")]
+    write!(writer, ",{}", trace.context)?;
+    writeln!(writer)
 }
 
 /// Writes the current thread's array of traces in a binary format.
@@ -84,11 +803,18 @@ pub fn write_traces_csv(writer: &mut impl Write) -> Result<()> {
 /// tag: u64
 /// start_rdtsc: u64
 /// stop_rdtsc: u64
+/// parent: u64, only with the `"parent"` feature
+/// aux: u64, only with the `"aux"` feature
+/// outcome: u64, only with the `"outcome"` feature
+/// request: u64, only with the `"request"` feature
+/// context: u64, only with the `"context"` feature
 ///
 /// There are no delimiters between each field or between traces.
 /// Assumes little-endian since this library only works for x86.
 /// Unlike print_csv, the difference between stop and start is not calculated.
-/// Writes the entire array, even zeroed / unused portions.
+/// Writes only the recorded portion of the array, so nothing when trace_count is 0,
+/// and only the traces passing with_export_filter if one is set.
+/// Once the array has wrapped, it's written starting from the oldest trace.
 ///
 /// This is suitable for import to Clickhouse via format RowBinary
 /// <https://clickhouse.com/docs/en/interfaces/formats#rowbinary>
@@ -96,25 +822,112 @@ pub fn write_traces_binary(writer: &mut impl Write) -> Result<()> {
     let mut res = Ok(());
     TSC_TRACE_SPANS.with(|spans| {
         let spans = spans.borrow();
-        let bytes: &[u8] = bytemuck::cast_slice(&*spans);
-        if let e @ Err(_) = writer.write_all(&bytes) {
-            res = e;
+        let index = TSC_TRACE_INDEX.with(Cell::get);
+        let (older, newer) = recorded(&spans[..], index);
+        if filter::filtering() {
+            for chunk in older.chunks_exact(RECORD_WIDTH_U64).chain(newer.chunks_exact(RECORD_WIDTH_U64)) {
+                if !filter::keep(&Trace::from_record(chunk)) {
+                    continue;
+                }
+                if let e @ Err(_) = writer.write_all(bytemuck::cast_slice(chunk)) {
+                    res = e;
+                    break;
+                }
+            }
+            return;
+        }
+        for part in [older, newer] {
+            let bytes: &[u8] = bytemuck::cast_slice(part);
+            if let e @ Err(_) = writer.write_all(bytes) {
+                res = e;
+                break;
+            }
         }
     });
     res
 }
 
+/// Splits the current thread's traces into windows of `window` duration by their start,
+/// counted from the earliest start, and writes each window that has traces to
+/// `dir/trace_N.bin` where N is the window number. `tsc_hz` is the counter frequency, see calibrate_hz.
+///
+/// Each file has a header followed by that window's traces in binary format, ordered by start.
+/// Returns the number of files written.
+pub fn write_traces_binary_windowed(dir: &Path, tsc_hz: u64, window: Duration) -> Result<usize> {
+    let traces = read_traces();
+    let Some(first) = traces.iter().map(|t| t.start).min() else {
+        return Ok(0);
+    };
+    let window_nanos = window.as_nanos().max(1);
+    let mut windows: BTreeMap<u128, Vec<Trace>> = BTreeMap::new();
+    for trace in traces {
+        let nanos = u128::from(cycles_to_nanos(trace.start - first, tsc_hz));
+        windows.entry(nanos / window_nanos).or_default().push(trace);
+    }
+    for (n, traces) in &mut windows {
+        traces.sort_by_key(|t| t.start);
+        let mut file = std::io::BufWriter::new(std::fs::File::create(dir.join(format!("trace_{n}.bin")))?);
+        write_paired_traces_header(&mut file)?;
+        for trace in traces.iter() {
+            file.write_all(bytemuck::cast_slice(&trace.to_record()))?;
+        }
+        file.flush()?;
+    }
+    Ok(windows.len())
+}
+
+/// `name` with characters other than letters, digits, `-` and `_` replaced by `_`,
+/// so it can't leave the directory it's joined to or be an invalid file name.
+pub(crate) fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Writes each of the current thread's top level spans, along with every span it contains, to its own file
+/// `dir/<name>_<start>.bin`, e.g. one file per request for archiving or replaying transactions individually.
+/// Roots are found with build_tree, and named by `names`, else their registered name, else their tag,
+/// with characters other than letters, digits, `-` and `_` replaced by `_`.
+///
+/// Each file has a header followed by the spans in binary format, ordered by start. Returns the number of files written.
+pub fn write_traces_by_root(dir: &Path, names: &std::collections::HashMap<u64, String>) -> Result<usize> {
+    let tree = build_tree(&read_traces());
+    for root in &tree.roots {
+        let name = names.get(&root.trace.tag).cloned().unwrap_or_else(|| export::tag_name(root.trace.tag));
+        let name = file_name_safe(&name);
+        let path = dir.join(format!("{name}_{}.bin", root.trace.start));
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write_paired_traces_header(&mut file)?;
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            file.write_all(bytemuck::cast_slice(&node.trace.to_record()))?;
+            stack.extend(node.children.iter().rev());
+        }
+        file.flush()?;
+    }
+    Ok(tree.roots.len())
+}
+
 /// Reads the processor's timestamp counter. If the `"lfence"` feature is enabled, includes lfence instructions before and after.
+///
+/// With the `"serializing_span"` feature rdtscp is used instead, which waits for earlier instructions to complete,
+/// so only the trailing lfence is added with `"lfence"`.
 #[inline(always)]
-#[cfg(target_arch = "x86")]
+#[cfg(all(target_arch = "x86", not(feature = "instant_clock")))]
 pub fn rdtsc() -> u64 {
     #[cfg(feature = "lfence")]
     use core::arch::x86::_mm_lfence;
+    #[cfg(feature = "serializing_span")]
+    use core::arch::x86::__rdtscp;
+    #[cfg(not(feature = "serializing_span"))]
     use core::arch::x86::_rdtsc;
     unsafe {
-        #[cfg(feature = "lfence")]
+        #[cfg(all(feature = "lfence", not(feature = "serializing_span")))]
         _mm_lfence();
+        #[cfg(not(feature = "serializing_span"))]
         let r = _rdtsc();
+        #[cfg(feature = "serializing_span")]
+        let r = __rdtscp(&mut 0);
         #[cfg(feature = "lfence")]
         _mm_lfence();
         r
@@ -122,16 +935,25 @@ pub fn rdtsc() -> u64 {
 }
 
 /// Reads the processor's timestamp counter. If the `"lfence"` feature is enabled, includes lfence instructions before and after.
+///
+/// With the `"serializing_span"` feature rdtscp is used instead, which waits for earlier instructions to complete,
+/// so only the trailing lfence is added with `"lfence"`.
 #[inline(always)]
-#[cfg(target_arch = "x86_64")]
+#[cfg(all(target_arch = "x86_64", not(feature = "instant_clock")))]
 pub fn rdtsc() -> u64 {
     #[cfg(feature = "lfence")]
     use core::arch::x86_64::_mm_lfence;
+    #[cfg(feature = "serializing_span")]
+    use core::arch::x86_64::__rdtscp;
+    #[cfg(not(feature = "serializing_span"))]
     use core::arch::x86_64::_rdtsc;
     unsafe {
-        #[cfg(feature = "lfence")]
+        #[cfg(all(feature = "lfence", not(feature = "serializing_span")))]
         _mm_lfence();
+        #[cfg(not(feature = "serializing_span"))]
         let r = _rdtsc();
+        #[cfg(feature = "serializing_span")]
+        let r = __rdtscp(&mut 0);
         #[cfg(feature = "lfence")]
         _mm_lfence();
         r
@@ -140,7 +962,7 @@ pub fn rdtsc() -> u64 {
 
 /// Workaround for ARM chips. Does not actually use rdtsc, as it is only supported on x86.
 #[inline(always)]
-#[cfg(target_arch = "aarch64")]
+#[cfg(all(target_arch = "aarch64", not(feature = "instant_clock")))]
 pub fn rdtsc() -> u64 {
     let r: u64;
     unsafe{
@@ -152,9 +974,21 @@ pub fn rdtsc() -> u64 {
     r
 }
 
-#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+/// With the `"instant_clock"` feature, nanoseconds since the first call in this process, from Instant.
+/// Starts at 1 so that no stop is ever 0, which marks an unused trace.
+#[inline(always)]
+#[cfg(feature = "instant_clock")]
+pub fn rdtsc() -> u64 {
+    static ORIGIN: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    ORIGIN.get_or_init(std::time::Instant::now).elapsed().as_nanos() as u64 + 1
+}
+
+#[cfg(all(
+    not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")),
+    not(feature = "instant_clock")
+))]
 pub fn rdtsc() -> u64 {
-    unimplemented!("x86 or x86_64 needed for rdtsc, aarch64 needed for workaround")
+    unimplemented!("x86 or x86_64 needed for rdtsc, aarch64 needed for workaround, or enable the \"instant_clock\" feature")
 }
 
 /// This struct must be public so that the trace_span! macro can make an instance of it in your code.
@@ -162,58 +996,357 @@ pub fn rdtsc() -> u64 {
 pub struct TraceSpan {
     tag: u64,
     start: u64,
+    #[cfg(feature = "parent")]
+    parent: u64,
 }
 
 impl TraceSpan {
-    /// Do not call this, use the trace_span! macro instead.
+    /// Starts a span that is recorded when dropped or passed to stop_span.
+    /// Prefer the trace_span! macro unless the span needs to be ended explicitly.
     pub fn new(tag: u64) -> Self {
+        let start = rdtsc();
+        #[cfg(feature = "enter_exit")]
+        insert_record(record(tag, start, start));
         TraceSpan {
             tag,
-            start: rdtsc(),
+            start,
+            #[cfg(feature = "parent")]
+            parent: 0,
+        }
+    }
+
+    /// Identity of this span for linking children to it, which is its start count.
+    pub fn id(&self) -> u64 {
+        self.start
+    }
+}
+
+/// One in how many spans started by trace_span_sampled! are currently recorded on this thread.
+///
+/// 1 while the thread's array is less than half full, then doubling each time the remaining space halves,
+/// so a fixed capacity covers a whole run at decreasing resolution instead of only its start.
+/// MAX_SAMPLE_RATE once the array is full or has wrapped.
+pub fn current_sample_rate() -> u32 {
+    let limit = limit();
+    let used = TSC_TRACE_INDEX.with(Cell::get);
+    if dropped_count() > 0 || used >= limit {
+        return MAX_SAMPLE_RATE;
+    }
+    if used * 2 < limit {
+        return 1;
+    }
+    let halvings = (limit / (2 * (limit - used))).max(1).next_power_of_two();
+    u32::try_from(halvings * 2).unwrap_or(MAX_SAMPLE_RATE).min(MAX_SAMPLE_RATE)
+}
+
+/// Do not call this, use the trace_span_sampled! macro instead.
+#[inline]
+pub fn sampled_span(tag: u64) -> Option<TraceSpan> {
+    let n = TSC_TRACE_SAMPLED.with(|sampled| {
+        let n = sampled.get();
+        sampled.set(n.wrapping_add(1));
+        n
+    });
+    n.is_multiple_of(current_sample_rate()).then(|| TraceSpan::new(tag))
+}
+
+/// Starts a span that is explicitly a child of `parent`, regardless of lexical nesting.
+/// Useful for callbacks and state machines, where containment would infer the wrong parent.
+///
+/// The link is only recorded with the `"parent"` feature, otherwise this is a plain span.
+/// build_tree prefers recorded links over start/stop containment.
+pub fn span_with_parent(tag: u64, parent: &TraceSpan) -> TraceSpan {
+    #[allow(unused_mut)]
+    let mut span = TraceSpan::new(tag);
+    #[cfg(feature = "parent")]
+    {
+        span.parent = parent.id();
+    }
+    #[cfg(not(feature = "parent"))]
+    let _ = parent;
+    span
+}
+
+impl TraceSpan {
+    /// Records the trace ending now, returning its cycles.
+    fn finish(&self) -> u64 {
+        let stop = rdtsc();
+        // with enter_exit, an exit event at stop, the enter event was recorded by new
+        let (tag, start) = if cfg!(feature = "enter_exit") {
+            (self.tag | EXIT_EVENT, stop)
+        } else {
+            (self.tag, self.start)
+        };
+        #[allow(unused_mut)]
+        let mut record = record(tag, start, stop);
+        #[cfg(feature = "parent")]
+        {
+            record[PARENT_FIELD] = self.parent;
         }
+        insert_record(record);
+        stop.saturating_sub(self.start)
     }
 }
 
 impl Drop for TraceSpan {
     fn drop(&mut self) {
-        let stop = rdtsc();
-        _insert_trace(self.tag, self.start, stop);
+        self.finish();
+    }
+}
+
+/// Ends `span` before it goes out of scope, recording its trace and returning its cycles,
+/// e.g. to decide whether to log a slow operation.
+pub fn stop_span(span: TraceSpan) -> u64 {
+    let cycles = span.finish();
+    std::mem::forget(span);
+    cycles
+}
+
+/// Span returned by span_with_cb, recorded when dropped and then passed to its callback.
+pub struct CallbackSpan<F: FnOnce(u64)> {
+    span: Option<TraceSpan>,
+    callback: Option<F>,
+}
+
+/// Starts a span that, when dropped, records its trace and then calls `callback` with its cycles,
+/// e.g. to warn about a slow operation where it happens.
+///
+/// The callback also runs when the span is dropped while unwinding from a panic.
+pub fn span_with_cb<F: FnOnce(u64)>(tag: u64, callback: F) -> CallbackSpan<F> {
+    CallbackSpan {
+        span: Some(TraceSpan::new(tag)),
+        callback: Some(callback),
+    }
+}
+
+impl<F: FnOnce(u64)> Drop for CallbackSpan<F> {
+    fn drop(&mut self) {
+        if let (Some(span), Some(callback)) = (self.span.take(), self.callback.take()) {
+            callback(stop_span(span));
+        }
+    }
+}
+
+/// Immediately inserts a trace into the thread local array, like insert_trace! but without the `as u64` casts.
+/// For code that already has exact u64 values.
+#[inline(always)]
+pub fn record_raw(tag: u64, start: u64, stop: u64) {
+    insert_record(record(tag, start, stop));
+}
+
+/// Like record_raw, with an auxiliary value stored alongside the trace, see insert_trace_aux!
+/// The value is only stored with the `"aux"` feature, otherwise this is a plain trace.
+#[inline(always)]
+pub fn record_raw_aux(tag: u64, start: u64, stop: u64, aux: u64) {
+    #[allow(unused_mut)]
+    let mut record = record(tag, start, stop);
+    #[cfg(feature = "aux")]
+    {
+        record[AUX_FIELD] = aux;
+    }
+    #[cfg(not(feature = "aux"))]
+    let _ = aux;
+    insert_record(record);
+}
+
+/// Like record_raw, marking the trace OUTCOME_OK or OUTCOME_ERR, see trace_span_result!
+/// The outcome is only stored with the `"outcome"` feature, otherwise this is a plain trace.
+#[inline(always)]
+pub fn record_raw_outcome(tag: u64, start: u64, stop: u64, ok: bool) {
+    #[allow(unused_mut)]
+    let mut record = record(tag, start, stop);
+    #[cfg(feature = "outcome")]
+    {
+        record[OUTCOME_FIELD] = if ok { OUTCOME_OK } else { OUTCOME_ERR };
+    }
+    #[cfg(not(feature = "outcome"))]
+    let _ = ok;
+    insert_record(record);
+}
+
+/// Runs `cmd` to completion and records a trace of `tag` covering it, from before it was spawned to after it exited,
+/// marked OUTCOME_OK if it exited successfully and OUTCOME_ERR otherwise with the `"outcome"` feature.
+/// Nothing is recorded if it couldn't be spawned, or with the `"off"` feature or a capacity of 0. Its output goes where `cmd` sends it, inherited by default.
+pub fn trace_command(tag: u64, cmd: &mut std::process::Command) -> Result<std::process::ExitStatus> {
+    if cfg!(feature = "off") {
+        return cmd.status();
     }
+    let start = rdtsc();
+    let status = cmd.status()?;
+    record_raw_outcome(tag, start, rdtsc(), status.success());
+    Ok(status)
 }
 
 /// Must be public for use by the insert_trace! macro.
-/// Use that macro instead, don't use this directly.
+/// Use that macro or record_raw instead, don't use this directly.
 #[inline(always)]
 pub fn _insert_trace(tag: u64, start: u64, stop: u64) {
+    record_raw(tag, start, stop);
+}
+
+/// Record with the given tag, start and stop, the current request_scope's id and the current context,
+/// any other feature added fields are 0.
+#[inline(always)]
+fn record(tag: u64, start: u64, stop: u64) -> [u64; RECORD_WIDTH_U64] {
+    let mut record = [0; RECORD_WIDTH_U64];
+    record[0] = tag;
+    record[1] = start;
+    record[2] = stop;
+    #[cfg(feature = "request")]
+    {
+        record[REQUEST_FIELD] = TSC_TRACE_REQUEST.with(Cell::get);
+    }
+    #[cfg(feature = "context")]
+    {
+        record[CONTEXT_FIELD] = TSC_TRACE_CONTEXT.with(Cell::get);
+    }
+    record
+}
+
+/// Stamps every trace recorded on the current thread with `ctx` until changed or cleared,
+/// e.g. a request id set at the top of a handler, for code not structured around request_scope's guard.
+///
+/// The value is only recorded with the `"context"` feature, otherwise this does nothing.
+pub fn set_context(ctx: u64) {
+    #[cfg(feature = "context")]
+    TSC_TRACE_CONTEXT.with(|context| context.set(ctx));
+    #[cfg(not(feature = "context"))]
+    let _ = ctx;
+}
+
+/// Stops stamping traces recorded on the current thread with a context, see set_context.
+pub fn clear_context() {
+    set_context(0);
+}
+
+/// Returned by request_scope, restores the previous request id when dropped.
+pub struct RequestGuard {
+    #[cfg(feature = "request")]
+    previous: u64,
+}
+
+/// Attributes every trace recorded on the current thread to request `id` until the returned guard is dropped,
+/// including those of helpers shared by other requests, so a shared buffer can be sliced per request.
+/// Scopes nest, dropping a guard restores the id that was active when it was created.
+///
+/// The id is only recorded with the `"request"` feature, otherwise this does nothing.
+#[must_use = "the request scope ends when the guard is dropped"]
+pub fn request_scope(id: u64) -> RequestGuard {
+    #[cfg(feature = "request")]
+    return RequestGuard {
+        previous: TSC_TRACE_REQUEST.with(|request| request.replace(id)),
+    };
+    #[cfg(not(feature = "request"))]
+    {
+        let _ = id;
+        RequestGuard {}
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "request")]
+        TSC_TRACE_REQUEST.with(|request| request.set(self.previous));
+    }
+}
+
+/// Inserts traces collected in a buffer on the caller's stack, e.g. by a function called millions of times,
+/// with one access to the thread local array instead of one per trace.
+///
+/// `buf` holds consecutive `tag, start, stop` triples, any trailing partial triple is ignored.
+/// Fields added by features are filled like record_raw does. Warmup and wrapping apply as if each was inserted separately.
+#[cfg_attr(feature = "off", allow(clippy::absurd_extreme_comparisons))]
+pub fn flush_stack_buffer(buf: &[u64]) {
+    let mut triples = buf.chunks_exact(3);
+    let warmup = TSC_TRACE_WARMUP_REMAINING.with(|remaining| {
+        let skip = remaining.get().min(triples.len());
+        remaining.set(remaining.get() - skip);
+        skip
+    });
+    let limit = limit();
+    if limit == 0 {
+        return;
+    }
+    TSC_TRACE_INDEX.with(|index| {
+        TSC_TRACE_SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            let mut i = index.get();
+            let mut dropped = 0;
+            for triple in triples.by_ref().skip(warmup) {
+                if i >= limit {
+                    i = 0;
+                }
+                let record = record(triple[0], triple[1], triple[2]);
+                #[cfg(feature = "const_array")]
+                {
+                    if spans[i + 2] != 0 {
+                        dropped += 1;
+                    }
+                    spans[i..i + RECORD_WIDTH_U64].copy_from_slice(&record);
+                }
+                #[cfg(not(feature = "const_array"))]
+                if spans.len() >= limit {
+                    dropped += 1;
+                    spans[i..i + RECORD_WIDTH_U64].copy_from_slice(&record);
+                } else {
+                    spans.extend_from_slice(&record);
+                }
+                i += RECORD_WIDTH_U64;
+            }
+            index.set(i);
+            TSC_TRACE_DROPPED.with(|d| d.set(d.get() + dropped));
+        })
+    })
+}
+
+#[inline(always)]
+#[cfg_attr(feature = "off", allow(clippy::absurd_extreme_comparisons))]
+fn insert_record(record: [u64; RECORD_WIDTH_U64]) {
+    if cfg!(feature = "off") {
+        return;
+    }
+    #[cfg(feature = "self_profile")]
+    let _self_profile = SelfProfile(rdtsc());
+    let warming_up = TSC_TRACE_WARMUP_REMAINING.with(|remaining| {
+        let n = remaining.get();
+        if n > 0 {
+            remaining.set(n - 1);
+        }
+        n > 0
+    });
+    if warming_up {
+        return;
+    }
+    let limit = limit();
+    if limit == 0 {
+        return;
+    }
     TSC_TRACE_INDEX.with(|index| {
         let mut i = index.get();
-        if i >= CAPACITY {
+        if i >= limit {
             i = 0;
         }
 
         #[cfg(feature = "const_array")]
         TSC_TRACE_SPANS.with(|spans| {
             let mut spans = spans.borrow_mut();
-            spans[i] = tag;
-            spans[i + 1] = start;
-            spans[i + 2] = stop;
-            i += 3;
+            if spans[i + 2] != 0 {
+                TSC_TRACE_DROPPED.with(|dropped| dropped.set(dropped.get() + 1));
+            }
+            spans[i..i + RECORD_WIDTH_U64].copy_from_slice(&record);
+            i += RECORD_WIDTH_U64;
         });
 
         #[cfg(not(feature = "const_array"))]
         TSC_TRACE_SPANS.with(|spans| {
             let mut spans = spans.borrow_mut();
-            if spans.len() >= CAPACITY {
-                spans[i] = tag;
-                spans[i + 1] = start;
-                spans[i + 2] = stop;
-                i += 3;
+            if spans.len() >= limit {
+                TSC_TRACE_DROPPED.with(|dropped| dropped.set(dropped.get() + 1));
+                spans[i..i + RECORD_WIDTH_U64].copy_from_slice(&record);
             } else {
-                spans.push(tag);
-                spans.push(start);
-                spans.push(stop);
-                i += 3;
+                spans.extend_from_slice(&record);
             }
+            i += RECORD_WIDTH_U64;
         });
 
         index.set(i);
@@ -236,6 +1369,37 @@ macro_rules! trace_span {
     ($e:expr) => {};
 }
 
+#[macro_export]
+#[cfg(all(feature = "packed_events", not(feature = "off")))]
+/// `event!(tag)` Records an ordering only event of the given tag as a single packed u64, see record_event.
+macro_rules! event {
+    ($e:expr) => {
+        record_event(($e) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(all(feature = "packed_events", feature = "off"))]
+macro_rules! event {
+    ($e:expr) => {};
+}
+
+#[macro_export]
+#[cfg(all(feature = "signal_safe", not(feature = "off")))]
+/// `mark!(tag)` Records a mark of the given u64 tag at the current counter value in a separate per thread ring,
+/// safe to use from a signal handler, see record_mark. Read them back with read_marks.
+macro_rules! mark {
+    ($e:expr) => {
+        record_mark(($e) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(all(feature = "signal_safe", feature = "off"))]
+macro_rules! mark {
+    ($e:expr) => {};
+}
+
 #[macro_export]
 #[cfg(not(feature = "off"))]
 /// `insert_trace!(tag, start, stop)`
@@ -252,3 +1416,448 @@ macro_rules! insert_trace {
 macro_rules! insert_trace {
     ($a:expr, $b:expr, $c:expr) => {};
 }
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `trace_span_sampled!(tag)` Like trace_span!, but only records one in current_sample_rate() spans,
+/// which adapts to how full the thread's array is so a whole run is covered at decreasing resolution.
+macro_rules! trace_span_sampled {
+    ($e:expr) => {
+        let _tsc_trace_span = sampled_span(($e) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! trace_span_sampled {
+    ($e:expr) => {};
+}
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `trace_span_when!(tag, payload, predicate)` Like trace_span!, but only if `predicate(payload)` returns true
+/// when the span starts, e.g. `trace_span_when!(UPLOAD, body.len(), |len| len > 1 << 20)` to trace only large requests.
+/// Otherwise no counter is read and nothing is stored.
+macro_rules! trace_span_when {
+    ($e:expr, $payload:expr, $predicate:expr) => {
+        let _tsc_trace_span = if ($predicate)($payload) {
+            Some(TraceSpan::new(($e) as u64))
+        } else {
+            None
+        };
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! trace_span_when {
+    ($e:expr, $payload:expr, $predicate:expr) => {};
+}
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `count!(tag)` or `count!(tag, n)` Adds 1 or n to a per tag counter, without recording a trace.
+/// Counters are per thread so counting doesn't contend, `counts()` sums them across threads.
+macro_rules! count {
+    ($e:expr) => {
+        count_tag(($e) as u64, 1);
+    };
+    ($e:expr, $n:expr) => {
+        count_tag(($e) as u64, ($n) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! count {
+    ($e:expr) => {};
+    ($e:expr, $n:expr) => {};
+}
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `insert_trace_aux!(tag, start, stop, aux)`
+/// Like insert_trace!, with a fourth expression stored in the aux field when the `"aux"` feature is enabled,
+/// e.g. a sampled address or perf sample id to join traces with hardware sampling data.
+macro_rules! insert_trace_aux {
+    ($a:expr, $b:expr, $c:expr, $d:expr) => {
+        record_raw_aux(($a) as u64, ($b) as u64, ($c) as u64, ($d) as u64);
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! insert_trace_aux {
+    ($a:expr, $b:expr, $c:expr, $d:expr) => {};
+}
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `trace_span_result!(tag, expr)` Evaluates expr, a Result, recording a trace of it that is marked
+/// OUTCOME_OK or OUTCOME_ERR with the `"outcome"` feature, and returns the Result.
+///
+/// `trace_span_result!(tag, expr, is_ok)` decides the outcome by calling `is_ok` with a reference to the value instead,
+/// for expressions that aren't a Result.
+macro_rules! trace_span_result {
+    ($tag:expr, $e:expr) => {
+        trace_span_result!($tag, $e, |r: &::core::result::Result<_, _>| r.is_ok())
+    };
+    ($tag:expr, $e:expr, $ok:expr) => {{
+        let _tsc_trace_start = rdtsc();
+        let _tsc_trace_value = $e;
+        let _tsc_trace_stop = rdtsc();
+        record_raw_outcome(($tag) as u64, _tsc_trace_start, _tsc_trace_stop, ($ok)(&_tsc_trace_value));
+        _tsc_trace_value
+    }};
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! trace_span_result {
+    ($tag:expr, $e:expr) => {
+        $e
+    };
+    ($tag:expr, $e:expr, $ok:expr) => {
+        $e
+    };
+}
+
+#[macro_export]
+#[cfg(not(feature = "off"))]
+/// `trace_loop!(tag, iterations, { loop })` Runs the block, recording one trace of it with `iterations`,
+/// evaluated after the block, stored in the aux field when the `"aux"` feature is enabled, and returns the block's value.
+/// E.g. `trace_loop!(7, n, { for x in xs { work(x); n += 1; } })`, so cycles per iteration can be read from a single trace.
+macro_rules! trace_loop {
+    ($tag:expr, $n:expr, $body:block) => {{
+        let _tsc_trace_start = rdtsc();
+        let _tsc_trace_value = $body;
+        let _tsc_trace_stop = rdtsc();
+        record_raw_aux(($tag) as u64, _tsc_trace_start, _tsc_trace_stop, ($n) as u64);
+        _tsc_trace_value
+    }};
+}
+
+#[macro_export]
+#[cfg(feature = "off")]
+macro_rules! trace_loop {
+    ($tag:expr, $n:expr, $body:block) => {
+        $body
+    };
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+
+    /// Inserts n traces tagged 0..n, each with a distinct non-zero start and stop.
+    fn insert_numbered(n: usize) {
+        for i in 0..n as u64 {
+            record_raw(i, i + 1, i + 2);
+        }
+    }
+
+    /// Tags of the current thread's traces, oldest first.
+    fn recorded_tags() -> Vec<u64> {
+        read_traces().iter().map(|t| t.tag).collect()
+    }
+
+    #[test]
+    fn no_wrap_keeps_everything() {
+        insert_numbered(10);
+        let traces = read_traces();
+        assert_eq!(traces.len(), 10);
+        assert!(traces.iter().enumerate().all(|(i, t)| t.tag == i as u64));
+        assert_eq!(dropped_count(), 0);
+    }
+
+    #[test]
+    fn exactly_full_does_not_drop() {
+        insert_numbered(TSC_TRACE_CAPACITY);
+        assert_eq!(read_traces().len(), TSC_TRACE_CAPACITY);
+        assert_eq!(dropped_count(), 0);
+    }
+
+    #[test]
+    fn wrap_keeps_most_recent_in_order() {
+        let overflow = 10;
+        insert_numbered(TSC_TRACE_CAPACITY + overflow);
+        let traces = read_traces();
+        assert_eq!(traces.len(), TSC_TRACE_CAPACITY);
+        for (i, trace) in traces.iter().enumerate() {
+            assert_eq!(trace.tag, (i + overflow) as u64);
+        }
+        assert_eq!(dropped_count(), overflow as u64);
+    }
+
+    #[test]
+    fn csv_line_of_inverted_span_has_zero_cycles() {
+        let inverted = Trace {
+            tag: 3,
+            start: 20,
+            stop: 10,
+            ..Default::default()
+        };
+        let mut line = vec![];
+        write_csv_line(&mut line, &inverted).unwrap();
+        assert!(String::from_utf8(line).unwrap().starts_with("3,20,10,0"));
+    }
+
+    #[test]
+    fn wrap_writes_csv_chronologically() {
+        let overflow = 3;
+        insert_numbered(TSC_TRACE_CAPACITY + overflow);
+        let mut csv = vec![];
+        write_traces_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some(format!("# tsc_trace_format_version={TRACE_FORMAT_VERSION}").as_str())
+        );
+        let tags: Vec<u64> = lines
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(tags.len(), TSC_TRACE_CAPACITY);
+        assert!(tags.windows(2).all(|w| w[0] + 1 == w[1]));
+        assert_eq!(tags[0], overflow as u64);
+    }
+
+    #[test]
+    fn wrap_writes_binary_chronologically() {
+        insert_numbered(TSC_TRACE_CAPACITY + 1);
+        let mut bin = vec![];
+        write_traces_binary(&mut bin).unwrap();
+        let traces = read_traces_binary(&mut &bin[..]).unwrap();
+        assert_eq!(traces, read_traces());
+        assert_eq!(traces[0].tag, 1);
+    }
+
+    #[test]
+    fn reads_enter_exit_dumps_as_spans() {
+        let header = TraceHeader {
+            version: TRACE_FORMAT_VERSION,
+            entries: vec![("events".to_string(), "enter_exit".to_string())],
+        };
+        let mut bin = vec![];
+        header.write(&mut bin).unwrap();
+        for event in [(5, 10, 10), (5 | EXIT_EVENT, 30, 30)] {
+            let trace = Trace {
+                tag: event.0,
+                start: event.1,
+                stop: event.2,
+                ..Default::default()
+            };
+            bin.extend_from_slice(bytemuck::cast_slice(&trace.to_record()));
+        }
+        let traces = read_traces_binary(&mut &bin[..]).unwrap();
+        assert_eq!(traces.len(), 1);
+        assert_eq!((traces[0].tag, traces[0].start, traces[0].stop), (5, 10, 30));
+    }
+
+    #[cfg(feature = "enter_exit")]
+    #[test]
+    fn exports_pair_enter_exit_events() {
+        {
+            trace_span!(7);
+            trace_span!(8);
+        }
+        let _open = TraceSpan::new(9);
+        let traces = read_traces();
+        assert_eq!(traces.iter().map(|t| t.tag).collect::<Vec<_>>(), vec![8, 7]);
+        assert!(traces[0].start >= traces[1].start && traces[0].stop <= traces[1].stop);
+        let mut csv = vec![];
+        write_traces_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(!csv.contains("events=enter_exit"));
+        assert_eq!(csv.lines().filter(|line| !line.starts_with('#')).count(), 2);
+    }
+
+    #[test]
+    fn append_moves_traces_an_export_filter_rejects() {
+        insert_numbered(3);
+        let sink = Mutex::new(vec![]);
+        with_export_filter(|t| t.tag == 1, || append_to(&sink));
+        let sink = sink.into_inner().unwrap();
+        assert_eq!(sink.iter().map(|t| t.tag).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(sink.iter().all(|t| t.thread == thread_number()));
+        assert!(read_traces().is_empty());
+    }
+
+    #[test]
+    fn clear_resets_dropped_count() {
+        insert_numbered(TSC_TRACE_CAPACITY + 1);
+        clear_traces();
+        assert_eq!(dropped_count(), 0);
+        assert!(read_traces().is_empty());
+        insert_numbered(2);
+        assert_eq!(read_traces().len(), 2);
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn zero_capacity_records_nothing() {
+        set_capacity_for_current_thread(0);
+        insert_numbered(3);
+        assert!(read_traces().is_empty());
+        init_capacity(0);
+        insert_numbered(3);
+        assert!(read_traces().is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn zero_capacity_entry_points_record_nothing() {
+        set_capacity_for_current_thread(0);
+        drop(span_with_cb(1, |_| {}));
+        assert!(trace_command(2, &mut std::process::Command::new("true")).is_ok());
+        record_raw_aux(3, 1, 2, 4);
+        assert!(read_traces().is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn prune_after_wrapping_keeps_order_and_room() {
+        set_capacity_for_current_thread(8);
+        insert_numbered(12);
+        // by tag and by age, tag i starts at i + 1
+        prune_traces(|t| t.tag % 2 == 0 && t.start > 6);
+        assert_eq!(recorded_tags(), vec![6, 8, 10]);
+        assert_eq!(trace_count(), 3);
+        assert_eq!(current_index(), 3);
+        assert_eq!(remaining_capacity(), 5);
+        record_raw(100, 20, 21);
+        assert_eq!(recorded_tags(), vec![6, 8, 10, 100]);
+        insert_numbered(5);
+        assert_eq!(recorded_tags(), vec![8, 10, 100, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn warmup_skips_the_first_traces_after_each_clear() {
+        set_warmup(3);
+        insert_numbered(5);
+        assert_eq!(recorded_tags(), vec![3, 4]);
+        flush_stack_buffer(&[7, 1, 2, 8, 3, 4]);
+        assert_eq!(recorded_tags(), vec![3, 4, 7, 8]);
+        clear_traces();
+        insert_numbered(4);
+        assert_eq!(recorded_tags(), vec![3]);
+        set_warmup(0);
+        insert_numbered(1);
+        assert_eq!(recorded_tags(), vec![3, 0]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn sampling_records_one_in_the_current_rate() {
+        set_capacity_for_current_thread(1000);
+        assert_eq!(current_sample_rate(), 1);
+        for _ in 0..10 {
+            trace_span_sampled!(7);
+        }
+        assert_eq!(read_traces().len(), 10);
+        clear_traces();
+        // half full halves the resolution, three quarters full halves it again
+        insert_numbered(500);
+        assert_eq!(current_sample_rate(), 2);
+        for _ in 0..100 {
+            trace_span_sampled!(7);
+        }
+        let sampled = read_traces().iter().filter(|t| t.tag == 7).count();
+        assert!((45..=55).contains(&sampled), "{sampled} of 100 spans at a rate of 2");
+        insert_numbered(750 - trace_count());
+        assert_eq!(current_sample_rate(), 4);
+        insert_numbered(1000 - trace_count());
+        assert_eq!(current_sample_rate(), MAX_SAMPLE_RATE);
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn peek_last_is_newest_in_order_across_the_wrap() {
+        set_capacity_for_current_thread(4);
+        assert!(peek_last(3).is_empty());
+        insert_numbered(2);
+        assert_eq!(peek_last(3).iter().map(|t| t.tag).collect::<Vec<_>>(), vec![0, 1]);
+        // once wrapped, the newest traces are split between the front and the end of the array
+        insert_numbered(5);
+        let tags = |n| peek_last(n).iter().map(|t| t.tag).collect::<Vec<_>>();
+        assert_eq!(tags(2), vec![3, 4]);
+        assert_eq!(tags(10), vec![1, 2, 3, 4]);
+        assert_eq!(recorded_tags(), vec![1, 2, 3, 4]);
+        assert!(tags(0).is_empty());
+    }
+
+    #[test]
+    fn stack_buffer_flushes_in_order_after_earlier_traces() {
+        record_raw(1, 1, 2);
+        let mut buf = [0; 3 * 3 + 2];
+        for (i, triple) in buf.chunks_exact_mut(3).enumerate() {
+            let i = i as u64;
+            triple.copy_from_slice(&[10 + i, 10 * i + 3, 10 * i + 5]);
+        }
+        // the trailing partial triple is ignored
+        buf[9..].copy_from_slice(&[99, 99]);
+        flush_stack_buffer(&buf);
+        let traces = read_traces();
+        assert_eq!(
+            traces.iter().map(|t| (t.tag, t.start, t.stop)).collect::<Vec<_>>(),
+            vec![(1, 1, 2), (10, 3, 5), (11, 13, 15), (12, 23, 25)]
+        );
+        record_raw(2, 30, 31);
+        assert_eq!(recorded_tags(), vec![1, 10, 11, 12, 2]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn stack_buffer_flush_wraps_like_single_inserts() {
+        set_capacity_for_current_thread(3);
+        flush_stack_buffer(&[1, 1, 2, 2, 3, 4, 3, 5, 6, 4, 7, 8, 5, 9, 10]);
+        assert_eq!(recorded_tags(), vec![3, 4, 5]);
+        assert_eq!(dropped_count(), 2);
+    }
+
+    #[test]
+    #[cfg(not(feature = "const_array"))]
+    fn resizing_keeps_the_newest_traces_in_order() {
+        set_capacity_for_current_thread(4);
+        insert_numbered(6);
+        assert_eq!(recorded_tags(), vec![2, 3, 4, 5]);
+        // growing a wrapped array unwraps it, so new traces follow the newest
+        assert_eq!(set_capacity_for_current_thread(6), 4);
+        assert_eq!(recorded_tags(), vec![2, 3, 4, 5]);
+        assert_eq!(remaining_capacity(), 2);
+        record_raw(10, 20, 21);
+        assert_eq!(recorded_tags(), vec![2, 3, 4, 5, 10]);
+        // shrinking below the number recorded keeps the newest and counts the rest as dropped
+        let dropped = dropped_count();
+        assert_eq!(set_capacity_for_current_thread(2), 6);
+        assert_eq!(recorded_tags(), vec![5, 10]);
+        assert_eq!(dropped_count(), dropped + 3);
+        record_raw(11, 22, 23);
+        assert_eq!(recorded_tags(), vec![10, 11]);
+    }
+
+    #[test]
+    fn by_root_writes_each_tree_to_a_named_file() {
+        let dir = std::env::temp_dir().join(format!("tsc-trace-by-root-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        record_raw(2, 20, 30);
+        record_raw(1, 10, 100);
+        record_raw(3, 200, 300);
+        record_raw(0xb007, 400, 500);
+        let names = std::collections::HashMap::from([(1, "request".to_string()), (3, "../up".to_string())]);
+        assert_eq!(write_traces_by_root(&dir, &names).unwrap(), 3);
+        let mut files: Vec<String> =
+            std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, ["45063_400.bin", "___up_200.bin", "request_10.bin"]);
+        let read = |file: &str| {
+            let mut reader = std::io::BufReader::new(std::fs::File::open(dir.join(file)).unwrap());
+            read_traces_binary(&mut reader).unwrap().iter().map(|t| (t.tag, t.start)).collect::<Vec<_>>()
+        };
+        assert_eq!(read("request_10.bin"), vec![(1, 10), (2, 20)]);
+        assert_eq!(read("___up_200.bin"), vec![(3, 200)]);
+        assert_eq!(read("45063_400.bin"), vec![(0xb007, 400)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
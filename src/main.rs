@@ -34,8 +34,9 @@ fn main() -> std::io::Result<()> {
 
     // can do runtime checks against configured capacity
     if TSC_TRACE_CAPACITY > 0 {
-        // write the array of traces to binary file
+        // write the array of traces to binary file, preceded by a header describing the format
         let mut bin = std::fs::File::create("/tmp/traces")?;
+        write_traces_header(&mut bin)?;
         write_traces_binary(&mut bin)?;
     } else {
         println!("tracing is off, not writing binary file");
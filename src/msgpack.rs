@@ -0,0 +1,158 @@
+use crate::export::tag_name;
+use crate::read_traces;
+use std::collections::HashMap;
+use std::io::{Result, Write};
+
+fn write_uint(writer: &mut impl Write, n: u64) -> Result<()> {
+    match n {
+        0..=0x7f => writer.write_all(&[n as u8]),
+        0x80..=0xff => writer.write_all(&[0xcc, n as u8]),
+        0x100..=0xffff => {
+            writer.write_all(&[0xcd])?;
+            writer.write_all(&(n as u16).to_be_bytes())
+        }
+        0x1_0000..=0xffff_ffff => {
+            writer.write_all(&[0xce])?;
+            writer.write_all(&(n as u32).to_be_bytes())
+        }
+        _ => {
+            writer.write_all(&[0xcf])?;
+            writer.write_all(&n.to_be_bytes())
+        }
+    }
+}
+
+fn write_str(writer: &mut impl Write, s: &str) -> Result<()> {
+    let len = s.len();
+    match len {
+        0..=31 => writer.write_all(&[0xa0 | len as u8])?,
+        32..=0xff => writer.write_all(&[0xd9, len as u8])?,
+        0x100..=0xffff => {
+            writer.write_all(&[0xda])?;
+            writer.write_all(&(len as u16).to_be_bytes())?
+        }
+        _ => {
+            writer.write_all(&[0xdb])?;
+            writer.write_all(&(len as u32).to_be_bytes())?
+        }
+    }
+    writer.write_all(s.as_bytes())
+}
+
+fn write_array_len(writer: &mut impl Write, len: usize) -> Result<()> {
+    match len {
+        0..=15 => writer.write_all(&[0x90 | len as u8]),
+        16..=0xffff => {
+            writer.write_all(&[0xdc])?;
+            writer.write_all(&(len as u16).to_be_bytes())
+        }
+        _ => {
+            writer.write_all(&[0xdd])?;
+            writer.write_all(&(len as u32).to_be_bytes())
+        }
+    }
+}
+
+/// Writes the current thread's traces as a MessagePack array of maps with the keys
+/// `tag`, `name`, `start`, `stop` and `cycles`, plus `parent`, `aux`, `outcome`, `request` and `context` when those features are enabled.
+///
+/// `name` is the tag's entry in `names`, or the tag number. Traces are oldest first, like read_traces.
+pub fn write_traces_msgpack(writer: &mut impl Write, names: &HashMap<u64, String>) -> Result<()> {
+    let traces = read_traces();
+    let fields = 5
+        + cfg!(feature = "parent") as u8
+        + cfg!(feature = "aux") as u8
+        + cfg!(feature = "outcome") as u8
+        + cfg!(feature = "request") as u8
+        + cfg!(feature = "context") as u8;
+    write_array_len(writer, traces.len())?;
+    for trace in &traces {
+        writer.write_all(&[0x80 | fields])?;
+        write_str(writer, "tag")?;
+        write_uint(writer, trace.tag)?;
+        write_str(writer, "name")?;
+        match names.get(&trace.tag) {
+            Some(name) => write_str(writer, name)?,
+            None => write_str(writer, &tag_name(trace.tag))?,
+        }
+        write_str(writer, "start")?;
+        write_uint(writer, trace.start)?;
+        write_str(writer, "stop")?;
+        write_uint(writer, trace.stop)?;
+        write_str(writer, "cycles")?;
+        write_uint(writer, trace.cycles())?;
+        #[cfg(feature = "parent")]
+        {
+            write_str(writer, "parent")?;
+            write_uint(writer, trace.parent)?;
+        }
+        #[cfg(feature = "aux")]
+        {
+            write_str(writer, "aux")?;
+            write_uint(writer, trace.aux)?;
+        }
+        #[cfg(feature = "outcome")]
+        {
+            write_str(writer, "outcome")?;
+            write_uint(writer, trace.outcome)?;
+        }
+        #[cfg(feature = "request")]
+        {
+            write_str(writer, "request")?;
+            write_uint(writer, trace.request)?;
+        }
+        #[cfg(feature = "context")]
+        {
+            write_str(writer, "context")?;
+            write_uint(writer, trace.context)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_raw;
+
+    fn encoded(write: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> Vec<u8> {
+        let mut out = vec![];
+        write(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn uints_use_the_smallest_encoding() {
+        assert_eq!(encoded(|w| write_uint(w, 0x7f)), [0x7f]);
+        assert_eq!(encoded(|w| write_uint(w, 0x80)), [0xcc, 0x80]);
+        assert_eq!(encoded(|w| write_uint(w, 0x100)), [0xcd, 0x01, 0x00]);
+        assert_eq!(encoded(|w| write_uint(w, 0x1_0000)), [0xce, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(encoded(|w| write_uint(w, u64::MAX)), [0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn encodes_a_known_trace() {
+        record_raw(5, 0x100, 0x180);
+        let names = HashMap::from([(5, "five".to_string())]);
+        let mut expected = vec![0x91, 0x80 | 5];
+        expected.extend_from_slice(b"\xa3tag\x05");
+        expected.extend_from_slice(b"\xa4name\xa4five");
+        expected.extend_from_slice(b"\xa5start\xcd\x01\x00");
+        expected.extend_from_slice(b"\xa4stop\xcd\x01\x80");
+        expected.extend_from_slice(b"\xa6cycles\xcc\x80");
+        // fields added by features follow in record order, all 0 for a raw trace
+        for (enabled, field) in [
+            (cfg!(feature = "parent"), &b"\xa6parent\x00"[..]),
+            (cfg!(feature = "aux"), b"\xa3aux\x00"),
+            (cfg!(feature = "outcome"), b"\xa7outcome\x00"),
+            (cfg!(feature = "request"), b"\xa7request\x00"),
+            (cfg!(feature = "context"), b"\xa7context\x00"),
+        ] {
+            if enabled {
+                expected[1] += 1;
+                expected.extend_from_slice(field);
+            }
+        }
+        assert_eq!(encoded(|w| write_traces_msgpack(w, &names)), expected);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::export::{tag_name, write_json_string};
+use crate::{cycles_to_nanos, with_traces};
+use std::collections::HashMap;
+use std::io::{Result, Write};
+
+/// Writes the current thread's traces as newline delimited JSON, one compact object per line:
+///
+/// `{"tag":1,"name":"parse","start":100,"stop":250,"cycles":150,"ns":50}`
+///
+/// `name` is the tag's entry in `names`, or the tag number, and `ns` converts cycles using the counter frequency `tsc_hz`.
+/// Traces are oldest first, like read_traces. As no line depends on another,
+/// this suits log pipelines and repeated flushes appended to one file.
+pub fn write_traces_ndjson(writer: &mut impl Write, names: &HashMap<u64, String>, tsc_hz: u64) -> Result<()> {
+    with_traces(|traces| {
+        for trace in traces {
+            write!(writer, "{{\"tag\":{},\"name\":", trace.tag)?;
+            match names.get(&trace.tag) {
+                Some(name) => write_json_string(writer, name)?,
+                None => write_json_string(writer, &tag_name(trace.tag))?,
+            }
+            writeln!(
+                writer,
+                ",\"start\":{},\"stop\":{},\"cycles\":{},\"ns\":{}}}",
+                trace.start,
+                trace.stop,
+                trace.cycles(),
+                cycles_to_nanos(trace.cycles(), tsc_hz)
+            )?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_raw;
+
+    #[test]
+    fn writes_one_object_per_trace() {
+        record_raw(1, 100, 250);
+        record_raw(2, 300, 303);
+        record_raw(0xd_150, 400, 700);
+        let names = HashMap::from([(1, "parse".to_string()), (2, "say \"hi\"".to_string())]);
+        let mut out = vec![];
+        write_traces_ndjson(&mut out, &names, 3_000_000_000).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "{\"tag\":1,\"name\":\"parse\",\"start\":100,\"stop\":250,\"cycles\":150,\"ns\":50}\n",
+                "{\"tag\":2,\"name\":\"say \\\"hi\\\"\",\"start\":300,\"stop\":303,\"cycles\":3,\"ns\":1}\n",
+                "{\"tag\":53584,\"name\":\"53584\",\"start\":400,\"stop\":700,\"cycles\":300,\"ns\":100}\n",
+            )
+        );
+    }
+}
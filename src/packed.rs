@@ -0,0 +1,86 @@
+use crate::TSC_TRACE_CAPACITY;
+use std::cell::{Cell, RefCell};
+use std::io::{Result, Write};
+
+/// low bits of a packed event holding its tag, the high bits hold its sequence number
+pub const PACKED_TAG_BITS: u32 = 16;
+
+const PACKED_TAG_MASK: u64 = (1 << PACKED_TAG_BITS) - 1;
+
+thread_local! {
+    static TSC_TRACE_EVENTS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    /// events ever recorded on this thread, the sequence number of the next one
+    static TSC_TRACE_EVENT_SEQUENCE: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Packs a sequence number and a tag into one u64, the tag truncated to PACKED_TAG_BITS.
+pub const fn pack_event(sequence: u64, tag: u64) -> u64 {
+    (sequence << PACKED_TAG_BITS) | (tag & PACKED_TAG_MASK)
+}
+
+/// Sequence number and tag of an event packed by pack_event.
+pub const fn unpack_event(event: u64) -> (u64, u64) {
+    (event >> PACKED_TAG_BITS, event & PACKED_TAG_MASK)
+}
+
+/// Records an event of `tag` as one packed u64 with the thread's next sequence number, without reading the counter.
+/// Use the event! macro instead.
+///
+/// Events go to their own array of TSC_TRACE_CAPACITY u64s per thread, wrapping like the trace array.
+/// Only the low PACKED_TAG_BITS of the tag are kept.
+#[inline(always)]
+#[cfg_attr(feature = "off", allow(clippy::absurd_extreme_comparisons))]
+pub fn record_event(tag: u64) {
+    if TSC_TRACE_CAPACITY == 0 {
+        return;
+    }
+    let sequence = TSC_TRACE_EVENT_SEQUENCE.with(|sequence| sequence.replace(sequence.get() + 1));
+    TSC_TRACE_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        if events.len() < TSC_TRACE_CAPACITY {
+            if events.capacity() == 0 {
+                events.reserve_exact(TSC_TRACE_CAPACITY);
+            }
+            events.push(pack_event(sequence, tag));
+        } else {
+            events[(sequence % TSC_TRACE_CAPACITY as u64) as usize] = pack_event(sequence, tag);
+        }
+    })
+}
+
+/// Calls `f` with the current thread's packed events, oldest first.
+#[cfg_attr(feature = "off", allow(clippy::absurd_extreme_comparisons))]
+fn with_events<R>(f: impl FnOnce(&mut dyn Iterator<Item = u64>) -> R) -> R {
+    let sequence = TSC_TRACE_EVENT_SEQUENCE.with(Cell::get);
+    TSC_TRACE_EVENTS.with(|events| {
+        let events = events.borrow();
+        let oldest = if events.len() < TSC_TRACE_CAPACITY {
+            0
+        } else {
+            (sequence % TSC_TRACE_CAPACITY as u64) as usize
+        };
+        f(&mut events[oldest..].iter().chain(&events[..oldest]).copied())
+    })
+}
+
+/// The current thread's events as (sequence, tag) pairs, oldest first.
+pub fn read_events() -> Vec<(u64, u64)> {
+    with_events(|events| events.map(unpack_event).collect())
+}
+
+/// Writes the current thread's packed events oldest first, each as one little-endian u64
+/// with no delimiters, see unpack_event.
+pub fn write_events_binary(writer: &mut impl Write) -> Result<()> {
+    with_events(|events| {
+        for event in events {
+            writer.write_all(&event.to_le_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+/// Discards the current thread's events and restarts their sequence numbers at 0.
+pub fn clear_events() {
+    TSC_TRACE_EVENTS.with(|events| events.borrow_mut().clear());
+    TSC_TRACE_EVENT_SEQUENCE.with(|sequence| sequence.set(0));
+}
@@ -0,0 +1,63 @@
+use crate::{file_name_safe, peek_last, thread_number, write_traces_header};
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// number of the panicking thread's most recent traces written by install_panic_dumper
+pub const PANIC_DUMP_TRACES: usize = 10_000;
+
+/// Writes the panicking thread's last traces to `dir`, returning the file written.
+fn dump(dir: &Path) -> Result<PathBuf> {
+    let traces = peek_last(PANIC_DUMP_TRACES);
+    let thread = std::thread::current();
+    let name = file_name_safe(thread.name().unwrap_or("thread"));
+    let path = dir.join(format!("panic-{name}-{}.bin", thread_number()));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    write_traces_header(&mut writer)?;
+    for trace in traces {
+        writer.write_all(bytemuck::cast_slice(&trace.to_record()))?;
+    }
+    writer.flush()?;
+    Ok(path)
+}
+
+/// Registers a panic hook that writes the panicking thread's last PANIC_DUMP_TRACES traces to
+/// `dir/panic-<thread name>-<thread id>.bin`, with the name sanitised like write_traces_by_root's, as a header and binary traces readable by read_traces_binary and the viewer,
+/// then runs the previously registered hook.
+///
+/// The traces are read with peek_last, so a panic inside this crate, while the array is borrowed, writes an empty dump
+/// instead of panicking again. Writing a dump allocates and does file IO, so it's not suitable for
+/// panics from allocation failures or signal handlers. Errors writing the dump are printed to stderr.
+pub fn install_panic_dumper(dir: impl AsRef<Path>) {
+    let dir = dir.as_ref().to_path_buf();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match dump(&dir) {
+            Ok(path) => eprintln!("tsc-trace: wrote the last traces of the panicking thread to {}", path.display()),
+            Err(e) => eprintln!("tsc-trace: failed to write the last traces of the panicking thread: {e}"),
+        }
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_names_stay_inside_the_directory() {
+        let dir = std::env::temp_dir().join(format!("tsc-trace-panic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let thread_dir = dir.clone();
+        let path = std::thread::Builder::new()
+            .name("../up/and away".to_string())
+            .spawn(move || dump(&thread_dir).unwrap())
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(path.parent(), Some(dir.as_path()));
+        assert!(path.file_name().unwrap().to_str().unwrap().starts_with("panic-___up_and_away-"));
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
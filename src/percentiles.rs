@@ -0,0 +1,158 @@
+use crate::with_traces;
+
+/// Index of percentile `p` (0 to 100) among `len` sorted values, by nearest rank.
+pub(crate) fn rank(p: f64, len: u64) -> u64 {
+    let rank = (p.clamp(0.0, 100.0) / 100.0 * len as f64).ceil() as u64;
+    rank.clamp(1, len.max(1)) - 1
+}
+
+/// Percentiles `ps` (0 to 100, e.g. `&[50.0, 95.0, 99.0]`) of the cycles of the current thread's traces of `tag`,
+/// using the nearest rank of the sorted cycles. Empty if no traces of `tag` were recorded.
+///
+/// Copies and sorts the cycles of every matching trace, see tag_percentiles_approx
+/// with the `"histogram"` feature to avoid that.
+pub fn tag_percentiles(tag: u64, ps: &[f64]) -> Vec<u64> {
+    let mut cycles: Vec<u64> =
+        with_traces(|traces| traces.filter(|t| t.tag == tag).map(|t| t.cycles()).collect());
+    if cycles.is_empty() {
+        return vec![];
+    }
+    cycles.sort_unstable();
+    ps.iter()
+        .map(|p| cycles[rank(*p, cycles.len() as u64) as usize])
+        .collect()
+}
+
+/// bits of precision kept below each power of two, 5 keeps values within 1/32
+#[cfg(feature = "histogram")]
+const SUB_BITS: u32 = 5;
+
+#[cfg(feature = "histogram")]
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+
+/// Fixed size log-linear histogram of cycle counts, for approximate percentiles
+/// without storing every value. Values are kept to within 1/32 of themselves.
+#[cfg(feature = "histogram")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleHistogram {
+    counts: Vec<u64>,
+    len: u64,
+}
+
+#[cfg(feature = "histogram")]
+impl Default for CycleHistogram {
+    fn default() -> Self {
+        CycleHistogram {
+            counts: vec![0; ((64 - SUB_BITS as u64 + 1) * SUB_BUCKETS) as usize],
+            len: 0,
+        }
+    }
+}
+
+#[cfg(feature = "histogram")]
+impl CycleHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bucket(cycles: u64) -> usize {
+        if cycles < SUB_BUCKETS {
+            return cycles as usize;
+        }
+        let exponent = 63 - cycles.leading_zeros();
+        let mantissa = cycles >> (exponent - SUB_BITS);
+        ((exponent - SUB_BITS + 1) as u64 * SUB_BUCKETS + mantissa - SUB_BUCKETS) as usize
+    }
+
+    /// largest value that falls in `bucket`
+    fn highest(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / SUB_BUCKETS - 1;
+        let mantissa = bucket % SUB_BUCKETS + SUB_BUCKETS;
+        (((mantissa + 1) as u128) << shift).saturating_sub(1).min(u64::MAX as u128) as u64
+    }
+
+    pub fn record(&mut self, cycles: u64) {
+        self.counts[Self::bucket(cycles)] += 1;
+        self.len += 1;
+    }
+
+    /// Number of values recorded.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Approximate percentile `p` (0 to 100), rounded up to the top of its bucket. 0 if empty.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        let rank = rank(p, self.len);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > rank {
+                return Self::highest(bucket);
+            }
+        }
+        unreachable!("rank is below len")
+    }
+}
+
+/// Like tag_percentiles, but streams the matching traces into a CycleHistogram
+/// rather than copying and sorting them, so percentiles are within 1/32 of the exact ones.
+#[cfg(feature = "histogram")]
+pub fn tag_percentiles_approx(tag: u64, ps: &[f64]) -> Vec<u64> {
+    let mut histogram = CycleHistogram::new();
+    with_traces(|traces| {
+        for trace in traces.filter(|t| t.tag == tag) {
+            histogram.record(trace.cycles());
+        }
+    });
+    if histogram.is_empty() {
+        return vec![];
+    }
+    ps.iter().map(|p| histogram.percentile(*p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_is_nearest_rank() {
+        assert_eq!(rank(0.0, 10), 0);
+        assert_eq!(rank(50.0, 10), 4);
+        assert_eq!(rank(95.0, 10), 9);
+        assert_eq!(rank(100.0, 10), 9);
+        assert_eq!(rank(50.0, 0), 0);
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn histogram_percentiles_within_a_32nd() {
+        let mut histogram = CycleHistogram::new();
+        for cycles in 1..=100_000 {
+            histogram.record(cycles);
+        }
+        for p in [1.0, 50.0, 90.0, 99.0, 100.0] {
+            let exact = rank(p, 100_000) + 1;
+            let approx = histogram.percentile(p);
+            assert!(approx >= exact && approx - exact <= exact / 32, "p{p}: {approx} vs {exact}");
+        }
+        // values below the sub buckets are exact
+        let mut small = CycleHistogram::new();
+        for cycles in 0..32 {
+            small.record(cycles);
+        }
+        assert_eq!(small.percentile(50.0), 15);
+        assert_eq!(CycleHistogram::new().percentile(50.0), 0);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::export::tag_name;
+use crate::percentiles::rank;
+use crate::{cycles_to_nanos, dropped_count, read_traces, validate_traces, Trace, ValidationIssue};
+use std::collections::HashMap;
+use std::io::{Result, Write};
+use std::time::Duration;
+
+/// number of the longest individual spans listed by write_report
+pub const REPORT_LONGEST_SPANS: usize = 10;
+
+/// Writes a plain text summary of the current thread's traces, for pasting into a ticket:
+/// the recording span and number of spans, a table per tag sorted by total cycles with count, mean, p50, p99 and max,
+/// the REPORT_LONGEST_SPANS longest spans, and any issues found by validate_traces.
+///
+/// Tags are named by their entry in `names`, or else by their registered name or number,
+/// and times are converted from cycles using the counter frequency `tsc_hz`.
+pub fn write_report(
+    writer: &mut impl Write,
+    names: &HashMap<u64, String>,
+    tsc_hz: u64,
+) -> Result<()> {
+    let traces = read_traces();
+    let name = |tag: u64| names.get(&tag).cloned().unwrap_or_else(|| tag_name(tag));
+    let time = |cycles: u64| {
+        format!(
+            "{:?}",
+            Duration::from_nanos(cycles_to_nanos(cycles, tsc_hz))
+        )
+    };
+
+    let start = traces.iter().map(|t| t.start).min().unwrap_or(0);
+    let stop = traces.iter().map(|t| t.stop).max().unwrap_or(0);
+    let span = stop.saturating_sub(start);
+    writeln!(writer, "recording span: {} ({span} cycles)", time(span))?;
+    writeln!(
+        writer,
+        "spans: {} ({} dropped when the array wrapped)",
+        traces.len(),
+        dropped_count()
+    )?;
+
+    let mut by_tag: HashMap<u64, Vec<u64>> = HashMap::new();
+    for trace in &traces {
+        by_tag.entry(trace.tag).or_default().push(trace.cycles());
+    }
+    let mut tags: Vec<(u64, u64, Vec<u64>)> = by_tag
+        .into_iter()
+        .map(|(tag, cycles)| (tag, cycles.iter().sum(), cycles))
+        .collect();
+    tags.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let width = tags
+        .iter()
+        .map(|(tag, ..)| name(*tag).len())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "{:<width$} {:>10} {:>16} {:>14} {:>12} {:>12} {:>12} {:>12}",
+        "tag", "count", "total cycles", "total time", "mean", "p50", "p99", "max"
+    )?;
+    for (tag, total, mut cycles) in tags {
+        cycles.sort_unstable();
+        let len = cycles.len() as u64;
+        writeln!(
+            writer,
+            "{:<width$} {len:>10} {total:>16} {:>14} {:>12} {:>12} {:>12} {:>12}",
+            name(tag),
+            time(total),
+            total / len,
+            cycles[rank(50.0, len) as usize],
+            cycles[rank(99.0, len) as usize],
+            cycles[cycles.len() - 1]
+        )?;
+    }
+
+    let mut longest: Vec<&Trace> = traces.iter().collect();
+    longest.sort_by_key(|t| std::cmp::Reverse(t.cycles()));
+    longest.truncate(REPORT_LONGEST_SPANS);
+    writeln!(writer)?;
+    writeln!(writer, "longest spans:")?;
+    for trace in longest {
+        writeln!(
+            writer,
+            "  {} at {}: {} cycles ({})",
+            name(trace.tag),
+            trace.start,
+            trace.cycles(),
+            time(trace.cycles())
+        )?;
+    }
+
+    let issues = validate_traces(&traces);
+    writeln!(writer)?;
+    writeln!(writer, "validation issues: {}", issues.len())?;
+    for issue in issues {
+        match issue {
+            ValidationIssue::Inverted(t) => writeln!(
+                writer,
+                "  inverted: {} starts at {} after stopping at {}",
+                name(t.tag),
+                t.start,
+                t.stop
+            )?,
+            ValidationIssue::Overlap { first, second } => writeln!(
+                writer,
+                "  overlap: {} at {}..{} and {}..{}",
+                name(first.tag),
+                first.start,
+                first.stop,
+                second.start,
+                second.stop
+            )?,
+            ValidationIssue::TooLong(t) => writeln!(
+                writer,
+                "  too long: {} at {}: {} cycles",
+                name(t.tag),
+                t.start,
+                t.cycles()
+            )?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_raw;
+
+    #[test]
+    fn reports_tags_longest_spans_and_issues() {
+        record_raw(1, 100, 110);
+        record_raw(1, 120, 140);
+        record_raw(2, 150, 250);
+        record_raw(1, 260, 290);
+        record_raw(3, 300, 295);
+        let names = HashMap::from([(1, "parse".to_string()), (2, "render".to_string())]);
+        let mut out = vec![];
+        write_report(&mut out, &names, 1_000_000_000).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert_eq!(
+            report.lines().collect::<Vec<_>>(),
+            [
+                "recording span: 195ns (195 cycles)",
+                "spans: 5 (0 dropped when the array wrapped)",
+                "",
+                "tag         count     total cycles     total time         mean          p50          p99          max",
+                "render          1              100          100ns          100          100          100          100",
+                "parse           3               60           60ns           20           20           30           30",
+                "3               1                0            0ns            0            0            0            0",
+                "",
+                "longest spans:",
+                "  render at 150: 100 cycles (100ns)",
+                "  parse at 260: 30 cycles (30ns)",
+                "  parse at 120: 20 cycles (20ns)",
+                "  parse at 100: 10 cycles (10ns)",
+                "  3 at 300: 0 cycles (0ns)",
+                "",
+                "validation issues: 1",
+                "  inverted: 3 starts at 300 after stopping at 295",
+            ]
+        );
+    }
+}
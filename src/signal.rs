@@ -0,0 +1,64 @@
+use crate::{rdtsc, Trace};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// number of marks kept per thread before the oldest are overwritten
+pub const MARK_CAPACITY: usize = 4096;
+
+thread_local! {
+    // const initialized atomics without Drop, so accessing them needs no lazy
+    // initialization or destructor registration that could allocate in a signal handler
+    /// tag and counter value of each mark, a counter value of 0 marking a slot being written
+    static TSC_TRACE_MARKS: [AtomicU64; MARK_CAPACITY * 2] =
+        const { [const { AtomicU64::new(0) }; MARK_CAPACITY * 2] };
+    /// marks ever reserved on this thread, the next slot is this modulo MARK_CAPACITY
+    static TSC_TRACE_MARK_INDEX: AtomicUsize = const { AtomicUsize::new(0) };
+}
+
+/// Records a mark of `tag` at the current counter value. Use the mark! macro instead.
+///
+/// Async-signal-safe: it only reads the counter and writes the thread's mark ring with atomics,
+/// never borrowing the trace array, allocating or locking, so it can be called from a signal handler
+/// such as a SIGPROF sampler, even one interrupting a mark. Not signal-safe with the `"instant_clock"` feature,
+/// whose clock is initialized lazily.
+#[inline(always)]
+pub fn record_mark(tag: u64) {
+    let tsc = rdtsc();
+    TSC_TRACE_MARKS.with(|marks| {
+        let slot = TSC_TRACE_MARK_INDEX.with(|index| index.fetch_add(1, Ordering::Relaxed)) % MARK_CAPACITY;
+        marks[slot * 2 + 1].store(0, Ordering::Relaxed);
+        marks[slot * 2].store(tag, Ordering::Relaxed);
+        marks[slot * 2 + 1].store(tsc, Ordering::Release);
+    })
+}
+
+/// The current thread's marks, oldest first, as traces whose start and stop are both the mark's counter value.
+/// Marks being written while reading are skipped. Not signal-safe.
+pub fn read_marks() -> Vec<Trace> {
+    TSC_TRACE_MARKS.with(|marks| {
+        let index = TSC_TRACE_MARK_INDEX.with(|index| index.load(Ordering::Acquire));
+        let first = index.saturating_sub(MARK_CAPACITY);
+        (first..index)
+            .filter_map(|i| {
+                let slot = i % MARK_CAPACITY;
+                let tsc = marks[slot * 2 + 1].load(Ordering::Acquire);
+                let tag = marks[slot * 2].load(Ordering::Relaxed);
+                (tsc != 0 && marks[slot * 2 + 1].load(Ordering::Acquire) == tsc).then_some(Trace {
+                    tag,
+                    start: tsc,
+                    stop: tsc,
+                    ..Trace::default()
+                })
+            })
+            .collect()
+    })
+}
+
+/// Discards the current thread's marks. Not signal-safe, don't call it while a handler may be marking.
+pub fn clear_marks() {
+    TSC_TRACE_MARKS.with(|marks| {
+        for mark in marks {
+            mark.store(0, Ordering::Relaxed);
+        }
+    });
+    TSC_TRACE_MARK_INDEX.with(|index| index.store(0, Ordering::Release));
+}
@@ -0,0 +1,396 @@
+use crate::export::{micros, tag_name, write_json_string};
+use crate::{build_tree, cycles_to_nanos, read_traces, read_traces_binary, SpanNode, Trace};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Result, Write};
+use std::thread::ThreadId;
+
+/// An owned collection of traces, for loading, filtering, merging and exporting them
+/// independently of the thread local array they were recorded into.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Traces {
+    pub traces: Vec<Trace>,
+    /// threads the traces came from when built by merge_threaded, indexed by Trace::thread minus one
+    pub threads: Vec<ThreadId>,
+}
+
+impl From<Vec<Trace>> for Traces {
+    fn from(traces: Vec<Trace>) -> Self {
+        Traces {
+            traces,
+            threads: vec![],
+        }
+    }
+}
+
+/// Combines traces collected from several threads into one timeline.
+///
+/// Every trace is rebased so the earliest start across all threads is 1, keeping threads aligned
+/// with each other since the counter is shared, and is tagged with its thread in Trace::thread.
+/// Exporters then give each thread its own lane. Rebased values start at 1 rather than 0 because a stop of 0
+/// marks an unused trace and a parent of 0 means no parent, so spans and links at the origin keep their meaning.
+pub fn merge_threaded(threads: Vec<(ThreadId, Traces)>) -> Traces {
+    let origin = threads
+        .iter()
+        .flat_map(|(_, t)| t.traces.iter().map(|t| t.start))
+        .min()
+        .unwrap_or(0);
+    let mut merged = Traces::default();
+    for (i, (id, traces)) in threads.into_iter().enumerate() {
+        merged.threads.push(id);
+        merged.traces.extend(traces.traces.into_iter().map(|mut t| {
+            let rebase = |value: u64| value.saturating_sub(origin).saturating_add(1);
+            t.start = rebase(t.start);
+            t.stop = rebase(t.stop);
+            if t.parent != 0 {
+                t.parent = rebase(t.parent);
+            }
+            t.thread = i as u64 + 1;
+            t
+        }));
+    }
+    merged
+}
+
+/// Cycle totals of one tag's traces, see Traces::tag_stats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TagStats {
+    pub count: u64,
+    pub total: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl TagStats {
+    /// total divided by count, 0 without traces
+    pub fn mean(&self) -> u64 {
+        self.total / self.count.max(1)
+    }
+}
+
+impl Traces {
+    /// Copy of the current thread's traces, see read_traces.
+    pub fn current_thread() -> Self {
+        read_traces().into()
+    }
+
+    /// Traces written by write_traces_binary, see read_traces_binary.
+    pub fn read_binary(reader: &mut impl BufRead) -> Result<Self> {
+        read_traces_binary(reader).map(Traces::from)
+    }
+
+    /// Keeps only the traces for which `keep` returns true.
+    pub fn retain(mut self, keep: impl FnMut(&Trace) -> bool) -> Self {
+        self.traces.retain(keep);
+        self
+    }
+
+    /// Replaces every trace's tag with `f(tag)`, e.g. to merge tags or to anonymize them before sharing a dump.
+    pub fn map_tags(mut self, mut f: impl FnMut(u64) -> u64) -> Self {
+        for trace in &mut self.traces {
+            trace.tag = f(trace.tag);
+        }
+        self
+    }
+
+    /// Shifts every start, stop and parent by `offset` cycles, saturating at 1 and u64::MAX,
+    /// as a stop of 0 marks an unused trace and a parent of 0 means no parent. Parents of 0 stay 0.
+    /// Starts saturate at 1 too, so a link to a span shifted to the floor still matches its start.
+    pub fn rebase(mut self, offset: i64) -> Self {
+        for trace in &mut self.traces {
+            trace.start = trace.start.saturating_add_signed(offset).max(1);
+            trace.stop = trace.stop.saturating_add_signed(offset).max(1);
+            if trace.parent != 0 {
+                trace.parent = trace.parent.saturating_add_signed(offset).max(1);
+            }
+        }
+        self
+    }
+
+    /// Count, total, min and max cycles of each tag's traces, the tags with the most total cycles first,
+    /// e.g. for a summary table of the most expensive tags.
+    pub fn tag_stats(&self) -> Vec<(u64, TagStats)> {
+        let mut tags: HashMap<u64, TagStats> = HashMap::new();
+        for trace in &self.traces {
+            let cycles = trace.cycles();
+            let stats = tags.entry(trace.tag).or_insert(TagStats {
+                min: cycles,
+                ..Default::default()
+            });
+            stats.count += 1;
+            stats.total += cycles;
+            stats.min = stats.min.min(cycles);
+            stats.max = stats.max.max(cycles);
+        }
+        let mut tags: Vec<(u64, TagStats)> = tags.into_iter().collect();
+        tags.sort_unstable_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(&b.0)));
+        tags
+    }
+
+    /// The traces of each tag, borrowed and in their order in `traces`.
+    /// HashMap iteration order is arbitrary, sort the tags for a deterministic order.
+    pub fn group_by_tag(&self) -> HashMap<u64, Vec<&Trace>> {
+        let mut tags: HashMap<u64, Vec<&Trace>> = HashMap::new();
+        for trace in &self.traces {
+            tags.entry(trace.tag).or_default().push(trace);
+        }
+        tags
+    }
+
+    fn min_start(&self) -> u64 {
+        self.traces.iter().map(|t| t.start).min().unwrap_or(0)
+    }
+
+    /// Traces grouped by Trace::thread, as spans only nest within a thread.
+    pub(crate) fn by_thread(&self) -> BTreeMap<u64, Vec<Trace>> {
+        let mut threads: BTreeMap<u64, Vec<Trace>> = BTreeMap::new();
+        for trace in &self.traces {
+            threads.entry(trace.thread).or_default().push(*trace);
+        }
+        threads
+    }
+
+    /// Writes the Chrome trace event format, loadable by chrome://tracing, Perfetto and others.
+    /// Timestamps are converted using the counter frequency `tsc_hz` and start from the earliest trace.
+    pub fn to_chrome_json(&self, writer: &mut impl Write, tsc_hz: u64) -> Result<()> {
+        let origin = self.min_start();
+        write!(writer, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+        for (i, trace) in self.traces.iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",")?;
+            }
+            write!(writer, "{{\"name\":")?;
+            write_json_string(writer, &tag_name(trace.tag))?;
+            write!(
+                writer,
+                ",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":0,\"tid\":{}}}",
+                micros(cycles_to_nanos(trace.start - origin, tsc_hz)),
+                micros(cycles_to_nanos(trace.cycles(), tsc_hz)),
+                trace.thread
+            )?;
+        }
+        writeln!(writer, "]}}")
+    }
+
+    /// Writes a speedscope evented profile <https://www.speedscope.app>, nested using build_tree,
+    /// with one profile per thread.
+    /// Times are in nanoseconds using the counter frequency `tsc_hz`, starting from the earliest trace.
+    pub fn to_speedscope(&self, writer: &mut impl Write, tsc_hz: u64) -> Result<()> {
+        let origin = self.min_start();
+        let mut frames: Vec<u64> = self.traces.iter().map(|t| t.tag).collect();
+        frames.sort_unstable();
+        frames.dedup();
+        let frame_index: HashMap<u64, usize> = frames.iter().enumerate().map(|(i, t)| (*t, i)).collect();
+
+        // speedscope needs properly nested events, so spans are clamped inside their parent
+        // and after their previous sibling rather than allowed to partially overlap
+        // walked with an explicit stack, as nesting can be arbitrarily deep
+        enum Step<'a> {
+            /// a span to open, with the close of its parent
+            Open(&'a SpanNode, u64),
+            /// the close of a span whose children have all been walked
+            Close(usize, u64),
+        }
+        let mut profiles = vec![];
+        for (thread, traces) in self.by_thread() {
+            let mut out = vec![];
+            let mut at = 0;
+            let tree = build_tree(&traces);
+            let mut stack: Vec<Step> = tree.roots.iter().rev().map(|root| Step::Open(root, u64::MAX)).collect();
+            while let Some(step) = stack.pop() {
+                match step {
+                    Step::Open(node, parent_close) => {
+                        let frame = frame_index[&node.trace.tag];
+                        let open = cycles_to_nanos(node.trace.start - origin, tsc_hz).clamp(at, parent_close);
+                        let close = cycles_to_nanos(node.trace.stop.max(node.trace.start) - origin, tsc_hz)
+                            .clamp(open, parent_close);
+                        out.push(('O', frame, open));
+                        at = open;
+                        stack.push(Step::Close(frame, close));
+                        stack.extend(node.children.iter().rev().map(|child| Step::Open(child, close)));
+                    }
+                    Step::Close(frame, close) => {
+                        out.push(('C', frame, close));
+                        at = close;
+                    }
+                }
+            }
+            profiles.push((thread, at, out));
+        }
+
+        write!(
+            writer,
+            "{{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\"shared\":{{\"frames\":["
+        )?;
+        for (i, tag) in frames.iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",")?;
+            }
+            write!(writer, "{{\"name\":")?;
+            write_json_string(writer, &tag_name(*tag))?;
+            write!(writer, "}}")?;
+        }
+        write!(writer, "]}},\"profiles\":[")?;
+        for (i, (thread, at, out)) in profiles.iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",")?;
+            }
+            let name = match thread {
+                0 => "tsc-trace".to_string(),
+                _ => format!("thread {thread}"),
+            };
+            write!(writer, "{{\"type\":\"evented\",\"name\":")?;
+            write_json_string(writer, &name)?;
+            write!(
+                writer,
+                ",\"unit\":\"nanoseconds\",\"startValue\":0,\"endValue\":{at},\"events\":["
+            )?;
+            for (i, (kind, frame, at)) in out.iter().enumerate() {
+                if i > 0 {
+                    write!(writer, ",")?;
+                }
+                write!(writer, "{{\"type\":\"{kind}\",\"frame\":{frame},\"at\":{at}}}")?;
+            }
+            write!(writer, "]}}")?;
+        }
+        writeln!(writer, "]}}")
+    }
+
+    /// Writes folded stacks, `root;child;leaf self_cycles` per line, as used by flamegraph tools.
+    /// Stacks are reconstructed with build_tree per thread and identical stacks are summed.
+    pub fn to_folded(&self, writer: &mut impl Write) -> Result<()> {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for traces in self.by_thread().values() {
+            // walked with an explicit stack, as nesting can be arbitrarily deep
+            let tree = build_tree(traces);
+            let mut pending: Vec<(&SpanNode, String)> =
+                tree.roots.iter().map(|root| (root, tag_name(root.trace.tag))).collect();
+            while let Some((node, stack)) = pending.pop() {
+                for child in &node.children {
+                    pending.push((child, format!("{stack};{}", tag_name(child.trace.tag))));
+                }
+                *stacks.entry(stack).or_default() += node.self_cycles;
+            }
+        }
+        for (stack, cycles) in stacks {
+            writeln!(writer, "{stack} {cycles}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(tag: u64, start: u64, stop: u64, parent: u64) -> Trace {
+        Trace {
+            tag,
+            start,
+            stop,
+            parent,
+            ..Default::default()
+        }
+    }
+
+    fn thread(traces: Vec<Trace>) -> (ThreadId, Traces) {
+        (std::thread::current().id(), traces.into())
+    }
+
+    #[test]
+    fn merge_aligns_threads_from_one() {
+        let merged = merge_threaded(vec![
+            thread(vec![span(1, 120, 150, 0)]),
+            thread(vec![span(2, 100, 110, 0)]),
+        ]);
+        assert_eq!(
+            merged.traces,
+            vec![
+                Trace { thread: 1, ..span(1, 21, 51, 0) },
+                Trace { thread: 2, ..span(2, 1, 11, 0) },
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_links_and_spans_at_origin() {
+        let traces = vec![span(1, 100, 100, 0), span(2, 105, 108, 100), span(3, 100, 120, 0)];
+        let merged = merge_threaded(vec![thread(traces)]);
+        let [instant, child, root] = merged.traces[..] else { panic!() };
+        assert_eq!((instant.start, instant.stop), (1, 1));
+        assert_eq!(child.parent, root.start);
+        assert_eq!(root.parent, 0);
+    }
+
+    #[test]
+    fn tag_stats_most_expensive_first() {
+        let traces = Traces::from(vec![span(1, 0, 10, 0), span(2, 0, 50, 0), span(1, 20, 24, 0)]);
+        let stats = traces.tag_stats();
+        assert_eq!(stats[0], (2, TagStats { count: 1, total: 50, min: 50, max: 50 }));
+        assert_eq!(stats[1], (1, TagStats { count: 2, total: 14, min: 4, max: 10 }));
+        assert_eq!(stats[1].1.mean(), 7);
+    }
+
+    #[test]
+    fn rebase_keeps_stops_and_links_non_zero() {
+        let traces = Traces::from(vec![span(1, 10, 20, 0), span(2, 12, 14, 10)]).rebase(-9);
+        assert_eq!(traces.traces, vec![span(1, 1, 11, 0), span(2, 3, 5, 1)]);
+        let traces = Traces::from(vec![span(1, 10, 10, 0), span(2, 10, 10, 10)]).rebase(-20);
+        assert_eq!(traces.traces, vec![span(1, 1, 1, 0), span(2, 1, 1, 1)]);
+    }
+
+    /// outer containing inner on one thread, and another inner on a second thread, at 1GHz
+    fn two_threads() -> Traces {
+        crate::register_tag_name(0x2150, "outer");
+        crate::register_tag_name(0x2151, "inner");
+        merge_threaded(vec![
+            thread(vec![span(0x2151, 110, 510, 0), span(0x2150, 10, 1010, 0)]),
+            thread(vec![span(0x2151, 2010, 2510, 0)]),
+        ])
+    }
+
+    fn written(write: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> String {
+        let mut out = vec![];
+        write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn chrome_json_golden() {
+        let traces = two_threads();
+        assert_eq!(
+            written(|w| traces.to_chrome_json(w, 1_000_000_000)),
+            concat!(
+                r#"{"displayTimeUnit":"ns","traceEvents":["#,
+                r#"{"name":"inner","ph":"X","ts":0.100,"dur":0.400,"pid":0,"tid":1},"#,
+                r#"{"name":"outer","ph":"X","ts":0.000,"dur":1.000,"pid":0,"tid":1},"#,
+                r#"{"name":"inner","ph":"X","ts":2.000,"dur":0.500,"pid":0,"tid":2}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn speedscope_golden() {
+        let traces = two_threads();
+        assert_eq!(
+            written(|w| traces.to_speedscope(w, 1_000_000_000)),
+            concat!(
+                r#"{"$schema":"https://www.speedscope.app/file-format-schema.json","#,
+                r#""shared":{"frames":[{"name":"outer"},{"name":"inner"}]},"profiles":["#,
+                r#"{"type":"evented","name":"thread 1","unit":"nanoseconds","startValue":0,"endValue":1000,"events":["#,
+                r#"{"type":"O","frame":0,"at":0},{"type":"O","frame":1,"at":100},"#,
+                r#"{"type":"C","frame":1,"at":500},{"type":"C","frame":0,"at":1000}]},"#,
+                r#"{"type":"evented","name":"thread 2","unit":"nanoseconds","startValue":0,"endValue":2500,"events":["#,
+                r#"{"type":"O","frame":1,"at":2000},{"type":"C","frame":1,"at":2500}]}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn folded_golden() {
+        let traces = two_threads();
+        // spans only nest within their thread, so the second thread's inner is a root
+        assert_eq!(written(|w| traces.to_folded(w)), "inner 500\nouter 600\nouter;inner 400\n");
+    }
+}
@@ -0,0 +1,170 @@
+use crate::Trace;
+use std::collections::HashMap;
+
+/// Call tree reconstructed from the start/stop containment of spans recorded on one thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpanTree {
+    /// spans not contained by any other span, ordered by start
+    pub roots: Vec<SpanNode>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpanNode {
+    pub trace: Trace,
+    /// stop minus start of this span
+    pub total_cycles: u64,
+    /// total_cycles minus the total_cycles of direct children
+    pub self_cycles: u64,
+    /// spans directly contained by this one, ordered by start
+    pub children: Vec<SpanNode>,
+}
+
+impl SpanTree {
+    /// Depth first iterator over every node, along with its depth (0 for roots).
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &SpanNode)> {
+        let mut stack: Vec<(usize, &SpanNode)> = self.roots.iter().rev().map(|n| (0, n)).collect();
+        std::iter::from_fn(move || {
+            let (depth, node) = stack.pop()?;
+            stack.extend(node.children.iter().rev().map(|n| (depth + 1, n)));
+            Some((depth, node))
+        })
+    }
+}
+
+/// Reconstructs the call tree of traces recorded on a single thread.
+///
+/// A span B is a child of A if A.start <= B.start and B.stop <= A.stop,
+/// so no depth needs to have been recorded. Traces may be in any order.
+/// Spans that partially overlap are treated as siblings.
+///
+/// Spans with an explicit parent link (see span_with_parent) are made children
+/// of the span whose start matches the link instead, when it is present in traces.
+pub fn build_tree(traces: &[Trace]) -> SpanTree {
+    let mut sorted = traces.to_vec();
+    // parents sort before children that start at the same time
+    sorted.sort_by(|a, b| a.start.cmp(&b.start).then(b.stop.cmp(&a.stop)));
+
+    // parent links are the start of the parent, the outermost span wins on equal starts
+    let mut by_start: HashMap<u64, usize> = HashMap::new();
+    if sorted.iter().any(|t| t.parent != 0) {
+        for (i, t) in sorted.iter().enumerate() {
+            by_start.entry(t.start).or_insert(i);
+        }
+    }
+
+    // children[i] holds indices into sorted, roots are children of None
+    let mut children: Vec<Vec<usize>> = vec![vec![]; sorted.len()];
+    let mut roots = vec![];
+    let mut stack: Vec<usize> = vec![];
+    for (i, t) in sorted.iter().enumerate() {
+        while let Some(&top) = stack.last() {
+            if sorted[top].stop >= t.stop {
+                break;
+            }
+            stack.pop();
+        }
+        // a linked parent always starts before its child, so it has already been visited
+        let explicit = by_start
+            .get(&t.parent)
+            .copied()
+            .filter(|&p| t.parent != 0 && sorted[p].start < t.start);
+        match explicit.or(stack.last().copied()) {
+            Some(parent) => children[parent].push(i),
+            None => roots.push(i),
+        }
+        stack.push(i);
+    }
+
+    // children always sort after their parent, so building from the last span back
+    // finds every child already built, without recursing once per nesting level
+    let mut built: Vec<Option<SpanNode>> = vec![None; sorted.len()];
+    for i in (0..sorted.len()).rev() {
+        let kids: Vec<SpanNode> = children[i].iter().filter_map(|&c| built[c].take()).collect();
+        let total_cycles = sorted[i].cycles();
+        let child_cycles: u64 = kids.iter().map(|k| k.total_cycles).sum();
+        built[i] = Some(SpanNode {
+            trace: sorted[i],
+            total_cycles,
+            self_cycles: total_cycles.saturating_sub(child_cycles),
+            children: kids,
+        });
+    }
+
+    SpanTree {
+        roots: roots.into_iter().filter_map(|i| built[i].take()).collect(),
+    }
+}
+
+impl Drop for SpanTree {
+    /// Drops nodes from an explicit stack, as the default drop recurses once per nesting level.
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.roots);
+        while let Some(mut node) = stack.pop() {
+            stack.append(&mut node.children);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(tag: u64, start: u64, stop: u64, parent: u64) -> Trace {
+        Trace {
+            tag,
+            start,
+            stop,
+            parent,
+            ..Default::default()
+        }
+    }
+
+    fn shape(tree: &SpanTree) -> Vec<(usize, u64, u64)> {
+        tree.iter().map(|(depth, node)| (depth, node.trace.tag, node.self_cycles)).collect()
+    }
+
+    #[test]
+    fn nests_by_containment() {
+        // partially overlapping 5 is a sibling of 1, and order doesn't matter
+        let traces = [
+            span(3, 20, 30, 0),
+            span(5, 95, 120, 0),
+            span(1, 0, 100, 0),
+            span(4, 50, 90, 0),
+            span(2, 10, 40, 0),
+        ];
+        let tree = build_tree(&traces);
+        assert_eq!(
+            shape(&tree),
+            vec![(0, 1, 30), (1, 2, 20), (2, 3, 10), (1, 4, 40), (0, 5, 25)]
+        );
+    }
+
+    #[test]
+    fn explicit_parents_override_containment() {
+        // 3 links to 2 without being inside it, 4 links to a span that isn't there
+        let traces = [
+            span(1, 10, 100, 0),
+            span(2, 20, 30, 0),
+            span(3, 40, 50, 20),
+            span(4, 60, 70, 999),
+        ];
+        let tree = build_tree(&traces);
+        assert_eq!(shape(&tree), vec![(0, 1, 70), (1, 2, 0), (2, 3, 10), (1, 4, 10)]);
+    }
+
+    #[test]
+    fn deep_nesting_does_not_overflow() {
+        let depth = 200_000;
+        let traces: Vec<Trace> = (0..depth)
+            .map(|i| Trace {
+                tag: 1,
+                start: 1 + i,
+                stop: 2 * depth - i,
+                ..Default::default()
+            })
+            .collect();
+        let tree = build_tree(&traces);
+        assert_eq!(tree.iter().map(|(d, _)| d).max(), Some(depth as usize - 1));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::Trace;
+use std::collections::HashMap;
+
+/// How many times the window covered by the other traces a span may reach outside of it before it's TooLong.
+/// Outermost spans legitimately reach outside it by their own untraced work, so this is generous.
+pub const TOO_LONG_FACTOR: u64 = 1000;
+
+/// An instrumentation mistake found by validate_traces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValidationIssue {
+    /// stop before start, e.g. from counters read on different cores or swapped arguments to insert_trace!
+    Inverted(Trace),
+    /// two spans of one tag on one thread that partially overlap, which nesting or recursion can't produce,
+    /// e.g. from a guard dropped out of order or mismatched raw starts and stops. `first` starts first.
+    Overlap { first: Trace, second: Trace },
+    /// a span reaching further before or after the window covered by all the other traces
+    /// than TOO_LONG_FACTOR times that window's length, e.g. from an uninitialized start of 0
+    TooLong(Trace),
+}
+
+/// Checks traces for spans that correct instrumentation can't produce, which would otherwise show up
+/// as misleading flamegraphs: inverted spans, partially overlapping spans of the same tag on the same thread,
+/// and spans longer than the recording window.
+///
+/// Threads are told apart by Trace::thread, so traces from merge_threaded can be checked together.
+/// Issues are returned grouped by kind, inverted spans first.
+pub fn validate_traces(traces: &[Trace]) -> Vec<ValidationIssue> {
+    let mut issues: Vec<ValidationIssue> = traces
+        .iter()
+        .filter(|t| t.stop < t.start)
+        .map(|t| ValidationIssue::Inverted(*t))
+        .collect();
+
+    let mut by_tag: HashMap<(u64, u64), Vec<Trace>> = HashMap::new();
+    for trace in traces.iter().filter(|t| t.stop >= t.start) {
+        by_tag.entry((trace.thread, trace.tag)).or_default().push(*trace);
+    }
+    let mut groups: Vec<((u64, u64), Vec<Trace>)> = by_tag.into_iter().collect();
+    groups.sort_unstable_by_key(|(key, _)| *key);
+    for (_, mut spans) in groups {
+        // outer spans sort before inner ones starting at the same time
+        spans.sort_by(|a, b| a.start.cmp(&b.start).then(b.stop.cmp(&a.stop)));
+        let mut open: Vec<Trace> = vec![];
+        for span in spans {
+            while open.last().is_some_and(|top| top.stop <= span.start) {
+                open.pop();
+            }
+            match open.last() {
+                Some(top) if span.stop > top.stop => issues.push(ValidationIssue::Overlap {
+                    first: *top,
+                    second: span,
+                }),
+                _ => open.push(span),
+            }
+        }
+    }
+
+    // the two lowest starts and highest stops, so each trace's window can exclude itself,
+    // of spans that aren't inverted, as those would shrink the window of the others
+    let spans: Vec<&Trace> = traces.iter().filter(|t| t.stop >= t.start).collect();
+    let mut starts = [u64::MAX; 2];
+    let mut stops = [0; 2];
+    for trace in &spans {
+        if trace.start < starts[1] {
+            starts[1] = trace.start;
+            starts.sort_unstable();
+        }
+        if trace.stop > stops[1] {
+            stops[1] = trace.stop;
+            stops.sort_unstable_by(|a, b| b.cmp(a));
+        }
+    }
+    if spans.len() > 1 {
+        for trace in spans {
+            let first = if trace.start == starts[0] { starts[1] } else { starts[0] };
+            let last = if trace.stop == stops[0] { stops[1] } else { stops[0] };
+            let window = last.saturating_sub(first).saturating_mul(TOO_LONG_FACTOR);
+            if trace.start.saturating_add(window) < first || trace.stop > last.saturating_add(window) {
+                issues.push(ValidationIssue::TooLong(*trace));
+            }
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(thread: u64, tag: u64, start: u64, stop: u64) -> Trace {
+        Trace {
+            tag,
+            start,
+            stop,
+            thread,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reports_inverted_spans() {
+        let traces = [span(0, 1, 0, 10), span(0, 2, 8, 5)];
+        assert_eq!(validate_traces(&traces), vec![ValidationIssue::Inverted(traces[1])]);
+    }
+
+    #[test]
+    fn reports_partial_overlaps_of_one_tag_on_one_thread() {
+        // recursion nests, other tags and other threads may overlap freely
+        let traces = [
+            span(0, 1, 0, 10),
+            span(0, 1, 2, 4),
+            span(0, 1, 5, 15),
+            span(0, 2, 9, 12),
+            span(1, 1, 9, 12),
+        ];
+        assert_eq!(
+            validate_traces(&traces),
+            vec![ValidationIssue::Overlap {
+                first: traces[0],
+                second: traces[2],
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_spans_far_outside_the_others() {
+        // a start of 0 stretches far before the window of the other traces
+        let traces = [
+            span(0, 1, 1_000_000, 1_000_010),
+            span(0, 2, 1_000_020, 1_000_030),
+            span(0, 3, 0, 1_000_030),
+        ];
+        assert_eq!(validate_traces(&traces), vec![ValidationIssue::TooLong(traces[2])]);
+    }
+}
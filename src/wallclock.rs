@@ -0,0 +1,169 @@
+use crate::{with_traces, write_csv_header, write_csv_line};
+use std::collections::BTreeMap;
+use std::io::{Result, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds since the Unix epoch of the counter value `tsc`, given that the counter read `anchor.0`
+/// at `anchor.1` and runs at `tsc_hz`. Computed in u128 / i128 so long runs don't overflow.
+fn unix_nanos(tsc: u64, anchor: (u64, SystemTime), tsc_hz: u64) -> i128 {
+    let (anchor_tsc, anchor_time) = anchor;
+    let anchor_nanos = match anchor_time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(before) => -(before.duration().as_nanos() as i128),
+    };
+    let delta = (tsc.abs_diff(anchor_tsc) as u128 * 1_000_000_000 / tsc_hz.max(1) as u128) as i128;
+    if tsc >= anchor_tsc {
+        anchor_nanos + delta
+    } else {
+        anchor_nanos - delta
+    }
+}
+
+/// Writes nanoseconds since the Unix epoch as an RFC 3339 UTC timestamp, e.g. `2024-05-01T12:00:00.000000001Z`.
+fn write_rfc3339(writer: &mut impl Write, unix_nanos: i128) -> Result<()> {
+    let secs = unix_nanos.div_euclid(1_000_000_000);
+    let nanos = unix_nanos.rem_euclid(1_000_000_000);
+    let days = secs.div_euclid(86_400);
+    let secs_of_day = secs.rem_euclid(86_400);
+    // civil date from days since 1970-01-01, in 400 year eras starting March 1st
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i128;
+    write!(
+        writer,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{nanos:09}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Like write_traces_csv, with each line starting with an extra column holding the span's start
+/// as an RFC 3339 UTC timestamp, to line spans up against application logs.
+///
+/// `anchor` is a counter value and the wall clock time it was read at, e.g. `(rdtsc(), SystemTime::now())`
+/// taken together, and `tsc_hz` the counter frequency, see calibrate_hz.
+pub fn write_traces_csv_wallclock(writer: &mut impl Write, anchor: (u64, SystemTime), tsc_hz: u64) -> Result<()> {
+    write_csv_header(writer)?;
+    with_traces(|traces| {
+        for trace in traces {
+            write_rfc3339(writer, unix_nanos(trace.start, anchor, tsc_hz))?;
+            write!(writer, ",")?;
+            write_csv_line(writer, &trace)?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes per tag totals of the current thread's traces in wall clock buckets of `bucket`, e.g. one second,
+/// as CSV rows `timestamp,tag,count,total_cycles,avg_cycles` after a line naming the columns,
+/// for time series databases and dashboards that want rates rather than individual spans.
+///
+/// Spans are counted in the bucket their start falls in, and buckets start at multiples of `bucket` since the Unix epoch,
+/// `timestamp` being the bucket's start as an RFC 3339 UTC timestamp. Rows are ordered by bucket then tag,
+/// and buckets without spans are left out. `anchor` and `tsc_hz` are as for write_traces_csv_wallclock.
+pub fn write_traces_timeseries(
+    writer: &mut impl Write,
+    anchor: (u64, SystemTime),
+    tsc_hz: u64,
+    bucket: Duration,
+) -> Result<()> {
+    let bucket = (bucket.as_nanos() as i128).max(1);
+    let mut totals: BTreeMap<(i128, u64), (u64, u64)> = BTreeMap::new();
+    with_traces(|traces| {
+        for trace in traces {
+            let start = unix_nanos(trace.start, anchor, tsc_hz).div_euclid(bucket) * bucket;
+            let (count, cycles) = totals.entry((start, trace.tag)).or_default();
+            *count += 1;
+            *cycles += trace.cycles();
+        }
+    });
+    writeln!(writer, "timestamp,tag,count,total_cycles,avg_cycles")?;
+    for ((start, tag), (count, cycles)) in totals {
+        write_rfc3339(writer, start)?;
+        writeln!(writer, ",{tag},{count},{cycles},{}", cycles / count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::_insert_trace;
+
+    fn rfc3339(unix_nanos: i128) -> String {
+        let mut out = vec![];
+        write_rfc3339(&mut out, unix_nanos).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn formats_civil_dates() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(rfc3339(1_714_564_800_000_000_001), "2024-05-01T12:00:00.000000001Z");
+        // 29 February of a leap year, then 1 March of a year that isn't
+        assert_eq!(rfc3339(1_709_164_800_000_000_000), "2024-02-29T00:00:00.000000000Z");
+        assert_eq!(rfc3339(1_677_628_800_000_000_000), "2023-03-01T00:00:00.000000000Z");
+        assert_eq!(rfc3339(-1), "1969-12-31T23:59:59.999999999Z");
+    }
+
+    #[test]
+    fn converts_counter_values_around_the_anchor() {
+        let anchor = (1_000, UNIX_EPOCH + Duration::from_secs(10));
+        assert_eq!(unix_nanos(1_000, anchor, 100), 10_000_000_000);
+        assert_eq!(unix_nanos(1_250, anchor, 100), 12_500_000_000);
+        // before the anchor
+        assert_eq!(unix_nanos(900, anchor, 100), 9_000_000_000);
+        assert_eq!(unix_nanos(0, anchor, 50), -10_000_000_000);
+    }
+
+    /// timeseries rows of the current thread's traces in 10ns buckets, with a counter running at 1GHz from the epoch
+    fn timeseries() -> Vec<String> {
+        let mut out = vec![];
+        write_traces_timeseries(&mut out, (0, UNIX_EPOCH), 1_000_000_000, Duration::from_nanos(10)).unwrap();
+        String::from_utf8(out).unwrap().lines().skip(1).map(String::from).collect()
+    }
+
+    #[test]
+    fn timeseries_bucket_edges_start_their_bucket() {
+        _insert_trace(1, 9, 10);
+        _insert_trace(1, 10, 12);
+        _insert_trace(1, 20, 26);
+        assert_eq!(
+            timeseries(),
+            [
+                "1970-01-01T00:00:00.000000000Z,1,1,1,1",
+                "1970-01-01T00:00:00.000000010Z,1,1,2,2",
+                "1970-01-01T00:00:00.000000020Z,1,1,6,6",
+            ]
+        );
+    }
+
+    #[test]
+    fn timeseries_counts_straddling_spans_where_they_start() {
+        _insert_trace(1, 15, 35);
+        _insert_trace(1, 12, 14);
+        assert_eq!(timeseries(), ["1970-01-01T00:00:00.000000010Z,1,2,22,11"]);
+    }
+
+    #[test]
+    fn timeseries_leaves_out_empty_buckets() {
+        _insert_trace(2, 11, 13);
+        _insert_trace(1, 12, 16);
+        _insert_trace(1, 41, 42);
+        assert_eq!(
+            timeseries(),
+            [
+                "1970-01-01T00:00:00.000000010Z,1,1,4,4",
+                "1970-01-01T00:00:00.000000010Z,2,1,2,2",
+                "1970-01-01T00:00:00.000000040Z,1,1,1,1",
+            ]
+        );
+    }
+}
@@ -2,6 +2,9 @@
 	tag_names: {
 		"1":"foo",
 	},
+	//tag_categories: {
+	//	"1":"io",
+	//},
 	default_args: [
 		"0",
 		"18446744073709551615",
@@ -12,4 +15,11 @@
 	window_height: 600, 
 	span_height: 15,
 	span_spacing: 1,
+	//multiplies span and text sizes, remove to use the monitor's scale factor
+	//ui_scale: 2.0,
+	heatmap: {
+		cold: [0, 0, 255],
+		hot: [255, 0, 0],
+		log_scale: true,
+	},
 }
\ No newline at end of file
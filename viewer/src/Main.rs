@@ -1,23 +1,60 @@
 pub mod config;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod views;
 
 use bytemuck::Pod;
 use bytemuck::Zeroable;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use tsc_trace::{pair_events, Trace, TraceHeader};
+use views::Filters;
+
+//the window viewer, left out of terminal only builds so they don't need sdl2
+#[cfg(feature = "gui")]
 use sdl2::event::{Event, WindowEvent};
+#[cfg(feature = "gui")]
 use sdl2::keyboard::Keycode;
+#[cfg(feature = "gui")]
+use sdl2::mouse::MouseButton;
+#[cfg(feature = "gui")]
 use sdl2::pixels::Color;
+#[cfg(feature = "gui")]
 use sdl2::rect::Rect;
+#[cfg(feature = "gui")]
 use sdl2::render::{TextureCreator, WindowCanvas};
+#[cfg(feature = "gui")]
 use sdl2::ttf::Font;
+#[cfg(feature = "gui")]
 use sdl2::video::WindowContext;
+#[cfg(feature = "gui")]
 use std::collections::HashMap;
-use std::env;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+#[cfg(feature = "gui")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "gui")]
 use std::thread;
+#[cfg(feature = "gui")]
 use std::time::{Duration, Instant};
+#[cfg(feature = "gui")]
+use tsc_trace::{build_tree, cycles_to_nanos};
+#[cfg(feature = "gui")]
+use views::View;
 
+#[cfg(feature = "gui")]
 const FRAME: u32 = 33_333_333;
+#[cfg(feature = "gui")]
+/// sizes of the sidebar at a ui_scale of 1
+const SIDEBAR_WIDTH: u32 = 200;
+#[cfg(feature = "gui")]
+const SIDEBAR_ROW_HEIGHT: i32 = 20;
+#[cfg(feature = "gui")]
+/// minimum horizontal pixels between gridlines
+const GRID_SPACING: u64 = 100;
+#[cfg(feature = "gui")]
+/// selection edges within this many pixels of a span boundary snap to it
+const SNAP_PIXELS: u64 = 4;
 
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(C)]
@@ -27,6 +64,15 @@ pub struct Span {
     stop: u64,
 }
 
+#[cfg(feature = "gui")]
+impl Span {
+    /// stop minus start, 0 for inverted spans rather than overflowing
+    fn cycles(&self) -> u64 {
+        self.stop.saturating_sub(self.start)
+    }
+}
+
+#[cfg(feature = "gui")]
 #[derive(Clone, Copy, Debug)]
 pub struct Area {
     y_start: u64,
@@ -36,18 +82,35 @@ pub struct Area {
     tag_data: Span,
 }
 
+#[cfg(feature = "gui")]
 #[derive(Clone, Copy, Debug)]
 pub struct Position {
     x: i32,
     y: i32,
 }
 
+#[cfg(feature = "gui")]
 pub struct App {
     window_width: u32,
     background_color: Color,
     draw_zones: Vec<Area>,
     colors: Vec<Color>,
     muted_colors: Vec<Color>,
+    /// color spans by duration rather than tag
+    heatmap: bool,
+    heatmap_config: config::HeatmapConfig,
+    /// shortest and longest span cycles, the ends of the heatmap gradient
+    min_cycles: u64,
+    max_cycles: u64,
+    tag_names: HashMap<u64, String>,
+    /// row of each tag when tags have categories, grouping the tags of a category together
+    lanes: Option<HashMap<u64, i32>>,
+    /// index of each tag's category in sorted order, used in place of the tag to pick colors
+    category_colors: HashMap<u64, usize>,
+    /// lay spans out in rows by nesting depth rather than by tag
+    depth_layout: bool,
+    /// nesting depth of each span by (tag, start, stop), inferred from start/stop containment
+    depths: HashMap<(u64, u64, u64), i32>,
     sdl_context: sdl2::Sdl,
     canvas: WindowCanvas,
     /// span cycles per horizontal pixel
@@ -56,14 +119,27 @@ pub struct App {
     span_height: i32,
     /// vertical pixels between spans
     span_spacing: i32,
+    /// multiplier of the configured sizes, the monitor's scale factor unless ui_scale is configured
+    ui_scale: f32,
+    /// drawable pixels per window coordinate, to convert mouse positions on HiDPI displays
+    pixel_ratio: f32,
+    sidebar_width: u32,
+    sidebar_row_height: i32,
     min_start: u64,
     max_stop: u64,
     scroll: i32,
     texture_creator: TextureCreator<WindowContext>,
+    video_subsystem: sdl2::VideoSubsystem,
+    /// filters currently applied to drawn spans
+    filters: Filters,
+    views: Vec<View>,
+    views_path: PathBuf,
+    selected_view: Option<usize>,
 }
 
+#[cfg(feature = "gui")]
 impl App {
-    pub fn new(filled_spans: &mut Vec<Span>) -> Result<App, String> {
+    pub fn new(filled_spans: &mut Vec<Span>, filters: Filters) -> Result<App, String> {
         let config = config::config();
         let mut spans: Vec<Span> = vec![];
         for span in filled_spans {
@@ -76,26 +152,67 @@ impl App {
         spans.sort_unstable_by_key(|s| s.start);
         let min_start = spans[0].start;
         let max_stop = spans.iter().max_by_key(|s| s.stop).unwrap().stop;
+        let min_cycles = spans.iter().map(|s| s.cycles()).min().unwrap();
+        let max_cycles = spans.iter().map(|s| s.cycles()).max().unwrap();
+        let traces: Vec<Trace> = spans
+            .iter()
+            .map(|s| Trace { tag: s.tag, start: s.start, stop: s.stop, ..Default::default() })
+            .collect();
+        let depths = build_tree(&traces)
+            .iter()
+            .map(|(depth, node)| ((node.trace.tag, node.trace.start, node.trace.stop), depth as i32))
+            .collect();
         let draw_zones: Vec<Area> = vec![];
         let window_width = config.window_width;
         let window_height = config.window_height;
         let sdl_context = sdl2::init()?;
         let video_subsystem = sdl_context.video()?;
-        let scale = (max_stop - min_start) / window_width as u64;
+        let trace_path = env::args().collect::<Vec<String>>()[1].clone();
+        let views_path = views::views_path(&trace_path);
+        let views = views::load_views(&views_path);
+        //session metadata from the header labels the window, so dumps can be told apart
+        let mut title = format!("{trace_path} covers {} cycles", max_stop.saturating_sub(min_start));
+        //names registered by the traced program, with names from config.js taking precedence
+        let mut tag_names = HashMap::new();
+        //categories set by the traced program, likewise overridden by config.js
+        let mut tag_categories = HashMap::new();
+        if let Ok(Some(header)) = File::open(&trace_path).and_then(|f| TraceHeader::read(&mut BufReader::new(f))) {
+            //the span is shown as wall-clock time when the dump records the counter rate, the core frequency differs from it
+            if let Some(hz) = header.counter_hz() {
+                let seconds = cycles_to_nanos(max_stop.saturating_sub(min_start), hz) as f64 / 1e9;
+                title = format!("{trace_path} covers {seconds:.6} s");
+            }
+            for (key, value) in header.entries.iter().filter(|(key, _)| !key.starts_with("tag_name.") && !key.starts_with("tag_category.")) {
+                title.push_str(&format!(" {key}={value}"));
+            }
+            tag_names = header.tag_names();
+            tag_categories = header.tag_categories();
+        }
+        tag_names.extend(config.tag_names.clone().unwrap_or_default());
+        tag_categories.extend(config.tag_categories.clone().unwrap_or_default());
+        let (lanes, category_colors) = Self::categorize(&spans, &tag_categories);
+        //text input is only wanted while naming a view, otherwise keys would also produce text events
+        video_subsystem.text_input().stop();
         let window = video_subsystem
             .window(
-                &env::args().collect::<Vec<String>>()[1],
+                &title,
                 window_width,
                 window_height,
             )
             .position_centered()
             .opengl()
             .resizable()
+            .allow_highdpi()
             .build()
             .map_err(|e| e.to_string())?;
         let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        //on HiDPI displays the canvas has more pixels than the window has coordinates, sizes are scaled to match
+        let pixel_ratio = canvas.output_size()?.0 as f32 / canvas.window().size().0.max(1) as f32;
+        let ui_scale = config.ui_scale.unwrap_or(pixel_ratio);
+        let window_width = canvas.output_size()?.0;
+        let scale = (max_stop.saturating_sub(min_start)) / window_width as u64;
         let texture_creator = canvas.texture_creator();
-        println!("Representing {0} clock cycles in {1} pixels for {2} cycles/pixel.", max_stop - min_start, window_width, scale);
+        println!("Representing {0} clock cycles in {1} pixels for {2} cycles/pixel.", max_stop.saturating_sub(min_start), window_width, scale);
 
         Ok(App {
             texture_creator,
@@ -124,17 +241,226 @@ impl App {
             .into_iter()
             .map(|c| Color::RGB(c.0, c.1, c.2))
             .collect(),
+            heatmap: false,
+            heatmap_config: config.heatmap.clone().unwrap_or_default(),
+            min_cycles,
+            max_cycles,
+            tag_names,
+            lanes,
+            category_colors,
+            depth_layout: false,
+            depths,
             sdl_context,
             canvas,
             scale,
-            span_height: config.span_height,
-            span_spacing: config.span_spacing,
+            span_height: (config.span_height as f32 * ui_scale).round() as i32,
+            span_spacing: (config.span_spacing as f32 * ui_scale).round() as i32,
+            ui_scale,
+            pixel_ratio,
+            sidebar_width: (SIDEBAR_WIDTH as f32 * ui_scale).round() as u32,
+            sidebar_row_height: (SIDEBAR_ROW_HEIGHT as f32 * ui_scale).round() as i32,
             min_start,
             max_stop,
             scroll: 0,
+            video_subsystem,
+            filters,
+            views,
+            views_path,
+            selected_view: None,
         })
     }
 
+    fn save_view(&mut self, name: String) {
+        let view = View {
+            name,
+            scale: self.scale,
+            scroll: self.scroll,
+            filters: self.filters,
+        };
+        //saving under an existing name replaces that view
+        match self.views.iter().position(|v| v.name == view.name) {
+            Some(i) => {
+                self.views[i] = view;
+                self.selected_view = Some(i);
+            }
+            None => {
+                self.views.push(view);
+                self.selected_view = Some(self.views.len() - 1);
+            }
+        }
+        match views::save_views(&self.views_path, &self.views) {
+            Ok(()) => println!("Saved views to {:?}", self.views_path),
+            Err(e) => println!("Failed to save views to {:?}: {e}", self.views_path),
+        }
+    }
+
+    fn select_view(&mut self, i: usize) {
+        let view = &self.views[i];
+        self.scale = view.scale;
+        self.scroll = view.scroll;
+        self.filters = view.filters;
+        self.selected_view = Some(i);
+        println!("View: {0}, scale: {1}, scroll: {2}, filters: {3:?}", view.name, view.scale, view.scroll, view.filters);
+    }
+
+    /// Converts the mouse positions of an event from window coordinates to the canvas pixels everything is drawn in.
+    fn to_pixels(&self, event: Event) -> Event {
+        let scale = |v: i32| (v as f32 * self.pixel_ratio) as i32;
+        match event {
+            Event::MouseButtonDown { timestamp, window_id, which, mouse_btn, clicks, x, y } => {
+                Event::MouseButtonDown { timestamp, window_id, which, mouse_btn, clicks, x: scale(x), y: scale(y) }
+            }
+            Event::MouseButtonUp { timestamp, window_id, which, mouse_btn, clicks, x, y } => {
+                Event::MouseButtonUp { timestamp, window_id, which, mouse_btn, clicks, x: scale(x), y: scale(y) }
+            }
+            Event::MouseMotion { timestamp, window_id, which, mousestate, x, y, xrel, yrel } => {
+                Event::MouseMotion { timestamp, window_id, which, mousestate, x: scale(x), y: scale(y), xrel: scale(xrel), yrel: scale(yrel) }
+            }
+            event => event,
+        }
+    }
+
+    fn sidebar_x(&self) -> i32 {
+        self.window_width.saturating_sub(self.sidebar_width) as i32
+    }
+
+    /// Returns the index of the view listed at a sidebar position, if any.
+    fn sidebar_row(&self, x: i32, y: i32) -> Option<usize> {
+        if self.views.is_empty() || x < self.sidebar_x() || y < 0 {
+            return None;
+        }
+        let row = (y / self.sidebar_row_height) as usize;
+        (row < self.views.len()).then_some(row)
+    }
+
+    fn draw_sidebar(&mut self, font: &sdl2::ttf::Font, naming: Option<&String>) -> Result<(), String> {
+        if self.views.is_empty() && naming.is_none() {
+            return Ok(());
+        }
+        let x = self.sidebar_x();
+        let rows = self.views.len() as i32 + naming.is_some() as i32;
+        self.canvas.set_draw_color(Color::RGB(224, 224, 224));
+        self.canvas.fill_rect(Rect::new(x, 0, self.sidebar_width, (rows * self.sidebar_row_height) as u32))?;
+        for (i, view) in self.views.iter().enumerate() {
+            let background = if self.selected_view == Some(i) {
+                Color::RGB(255, 255, 255)
+            } else {
+                Color::RGB(224, 224, 224)
+            };
+            Self::draw_label(&mut self.canvas, &self.texture_creator, font, x, i as i32 * self.sidebar_row_height, &view.name, background, self.sidebar_width, self.ui_scale)?;
+        }
+        if let Some(name) = naming {
+            let text = format!("name: {name}_");
+            Self::draw_label(&mut self.canvas, &self.texture_creator, font, x, self.views.len() as i32 * self.sidebar_row_height, &text, Color::RGB(255, 255, 192), self.sidebar_width, self.ui_scale)?;
+        }
+        Ok(())
+    }
+
+    fn draw_label(
+        canvas: &mut WindowCanvas,
+        texture_creator: &TextureCreator<WindowContext>,
+        font: &sdl2::ttf::Font,
+        x: i32,
+        y: i32,
+        text: &str,
+        background: Color,
+        width: u32,
+        ui_scale: f32,
+    ) -> Result<(), String> {
+        let height = (SIDEBAR_ROW_HEIGHT as f32 * ui_scale).round() as i32;
+        canvas.set_draw_color(background);
+        canvas.fill_rect(Rect::new(x, y, width, height as u32))?;
+        if text.is_empty() {
+            return Ok(());
+        }
+        let surface = font
+            .render(text)
+            .blended(Color::RGBA(0, 0, 0, 128))
+            .map_err(|e| e.to_string())?;
+        let texture = texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string())?;
+        //8 pixels per character at a ui_scale of 1, squeezed to fit the width
+        let width = ((text.len() as f32 * 8.0 * ui_scale) as u32).min(width);
+        let margin = (2.0 * ui_scale).round() as i32;
+        canvas.copy(&texture, None, Some(Rect::new(x, y + margin, width, (height - 2 * margin).max(1) as u32)))?;
+        Ok(())
+    }
+
+    /// cycle count shown at a horizontal pixel position
+    fn cycle_at(&self, x: i32) -> u64 {
+        (self.min_start as i64 + (x as i64 + self.scroll as i64) * (self.scale as i64 + 1)).max(0) as u64
+    }
+
+    /// horizontal pixel position of a cycle count
+    fn x_at(&self, cycle: u64) -> i32 {
+        ((cycle.saturating_sub(self.min_start) / (self.scale + 1)) as i64 - self.scroll as i64)
+            .clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+
+    /// Moves a cycle count to the nearest span boundary if one is within SNAP_PIXELS, boundaries must be sorted.
+    fn snap(&self, cycle: u64, boundaries: &[u64]) -> u64 {
+        let threshold = SNAP_PIXELS * (self.scale + 1);
+        let i = boundaries.partition_point(|b| *b < cycle);
+        [i.checked_sub(1), Some(i)]
+            .into_iter()
+            .flatten()
+            .filter_map(|i| boundaries.get(i).copied())
+            .filter(|b| b.abs_diff(cycle) <= threshold)
+            .min_by_key(|b| b.abs_diff(cycle))
+            .unwrap_or(cycle)
+    }
+
+    /// Faint vertical lines at round numbers of cycles, at least GRID_SPACING pixels apart.
+    fn draw_grid(&mut self) -> Result<(), String> {
+        let (_, height) = self.canvas.output_size()?;
+        let min_interval = GRID_SPACING * (self.scale + 1);
+        //1, 2 or 5 times a power of ten
+        let mut interval = 1;
+        while interval < min_interval {
+            interval = match interval.to_string().as_bytes()[0] {
+                b'1' => interval * 2,
+                b'2' => interval / 2 * 5,
+                _ => interval * 2,
+            };
+        }
+        self.canvas.set_draw_color(Color::RGB(176, 176, 176));
+        let mut cycle = self.cycle_at(0) / interval * interval;
+        while self.x_at(cycle) < self.window_width as i32 {
+            let x = self.x_at(cycle);
+            if x >= 0 {
+                self.canvas.draw_line((x, 0), (x, height as i32))?;
+            }
+            cycle += interval;
+        }
+        Ok(())
+    }
+
+    /// Reports the cycle range and the count and total cycles of drawn spans fully inside a selection box.
+    fn select(&self, spans: &[Span], from: (i32, i32), to: (i32, i32), boundaries: &[u64]) -> String {
+        let start = self.snap(self.cycle_at(from.0.min(to.0)), boundaries);
+        let stop = self.snap(self.cycle_at(from.0.max(to.0)), boundaries);
+        let (top, bottom) = (from.1.min(to.1), from.1.max(to.1));
+        let inside: Vec<&Span> = spans
+            .iter()
+            .filter(|s| self.filters.contains(s.tag, s.start))
+            .filter(|s| s.start >= start && s.stop <= stop)
+            .filter(|s| self.y_pos(s) + self.span_height > top && self.y_pos(s) < bottom)
+            .collect();
+        let total: u64 = inside.iter().map(|s| s.cycles()).sum();
+        format!("{start}..{stop}: {} cycles, {} spans, {total} span cycles", stop.saturating_sub(start), inside.len())
+    }
+
+    /// Color along the heatmap gradient for a span's duration.
+    fn heat_color(&self, span: &Span) -> Color {
+        let scale = |c: u64| if self.heatmap_config.log_scale { ((c + 1) as f64).ln() } else { c as f64 };
+        let (low, high) = (scale(self.min_cycles), scale(self.max_cycles));
+        let t = if high > low { (scale(span.cycles()) - low) / (high - low) } else { 1.0 };
+        let (cold, hot) = (self.heatmap_config.cold, self.heatmap_config.hot);
+        let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+        Color::RGB(mix(cold.0, hot.0), mix(cold.1, hot.1), mix(cold.2, hot.2))
+    }
+
     fn draw_span(&mut self, span: &Span) {
         let x_sz = self.x_size(span);
         let scrolled_x = self.x_pos(span).saturating_sub(self.scroll);
@@ -146,10 +472,7 @@ impl App {
                 .expect("Window width couldn't be parsed")
             && (scrolled_x + x_sz as i32) > 0
         {
-            //using lighter color palette if multiple small spans could occupy the same pixel
-            self.canvas.set_draw_color(if x_sz < 1 {
-                self.colors[span.tag as usize % self.colors.len()]
-            } else {
+            if x_sz >= 1 {
                 self.draw_zones.push(Area {
                     y_start: self.y_pos(span) as u64,
                     x_start: scrolled_x,
@@ -157,7 +480,14 @@ impl App {
                     x_stop: (scrolled_x + x_sz as i32),
                     tag_data: *span,
                 });
-                self.muted_colors[span.tag as usize % self.muted_colors.len()]
+            }
+            //using lighter color palette if multiple small spans could occupy the same pixel
+            self.canvas.set_draw_color(if self.heatmap {
+                self.heat_color(span)
+            } else if x_sz < 1 {
+                self.colors[self.color_index(span) % self.colors.len()]
+            } else {
+                self.muted_colors[self.color_index(span) % self.muted_colors.len()]
             });
             self.canvas
                 .fill_rect(Rect::new(
@@ -171,7 +501,7 @@ impl App {
     }
 
     fn x_size(&self, span: &Span) -> u32 {
-        ((span.stop - span.start) / (self.scale + 1))
+        (span.cycles() / (self.scale + 1))
             .try_into()
             .unwrap_or_else(|e| {
                 panic!(
@@ -187,10 +517,53 @@ impl App {
             .unwrap_or_else(|_| i32::MAX)
     }
 
+    /// Rows grouping the tags of each category together, categories in sorted order and uncategorized tags last,
+    /// and the index of each categorized tag's category for coloring. No rows without any categories.
+    fn categorize(spans: &[Span], tag_categories: &HashMap<u64, String>) -> (Option<HashMap<u64, i32>>, HashMap<u64, usize>) {
+        if tag_categories.is_empty() {
+            return (None, HashMap::new());
+        }
+        let mut categories: Vec<&String> = tag_categories.values().collect();
+        categories.sort_unstable();
+        categories.dedup();
+        let category_colors: HashMap<u64, usize> = tag_categories
+            .iter()
+            .map(|(tag, category)| (*tag, categories.binary_search(&category).unwrap()))
+            .collect();
+        let mut tags: Vec<u64> = spans.iter().map(|s| s.tag).collect();
+        tags.sort_unstable();
+        tags.dedup();
+        tags.sort_by_key(|tag| (category_colors.get(tag).copied().unwrap_or(usize::MAX), *tag));
+        let lanes = tags.into_iter().enumerate().map(|(row, tag)| (tag, row as i32)).collect();
+        (Some(lanes), category_colors)
+    }
+
+    /// Index into the color lists for a span, its category if it has one, otherwise its tag.
+    fn color_index(&self, span: &Span) -> usize {
+        self.category_colors.get(&span.tag).copied().unwrap_or(span.tag as usize)
+    }
+
     fn y_pos(&self, span: &Span) -> i32 {
-        let tag: i32 = span.tag.try_into()
-            .expect("not intended to handle very high span tag cardinality, try filtering / renumbering first: {span}");
-        ((self.span_spacing + self.span_height) * tag) + self.span_spacing
+        let row: i32 = if self.depth_layout {
+            self.depths.get(&(span.tag, span.start, span.stop)).copied().unwrap_or(0)
+        } else if let Some(lanes) = &self.lanes {
+            lanes.get(&span.tag).copied().unwrap_or(0)
+        } else {
+            span.tag.try_into()
+                .expect("not intended to handle very high span tag cardinality, try filtering / renumbering first: {span}")
+        };
+        ((self.span_spacing + self.span_height) * row) + self.span_spacing
+    }
+
+    /// One entry per drawn row, tracking the last x drawn in it.
+    fn rows(&self, spans: &[Span]) -> Vec<Position> {
+        let mut all_spans_map: HashMap<i32, i32> = HashMap::new();
+        //getting each y position to be drawn
+        for span in spans {
+            all_spans_map.insert(self.y_pos(span), -1);
+        }
+        //converting the HashMap to a Vec for increased performance on small numbers of tags
+        all_spans_map.into_iter().map(|(y, x)| Position { x, y }).collect()
     }
 
     fn draw_text(
@@ -200,21 +573,14 @@ impl App {
         x: i32,
         y: i32,
         tag_data: &Span,
+        tag_names: &HashMap<u64, String>,
+        ui_scale: f32,
     ) -> Result<(), String> {
-        let config = config::config();
-        let tag_text: String;
-        match config.tag_names {
-            Some(hashmap) => {
-                tag_text = hashmap
-                    .get(&tag_data.tag)
-                    .map(|s| s.to_string())
-                    .unwrap_or_else(|| tag_data.tag.to_string());
-            }
-            None => {
-                tag_text = tag_data.tag.to_string();
-            }
-        }
-        let tag_text = format!("{0},{1}", tag_text, (tag_data.stop - tag_data.start));
+        let tag_text = tag_names
+            .get(&tag_data.tag)
+            .cloned()
+            .unwrap_or_else(|| tag_data.tag.to_string());
+        let tag_text = format!("{0},{1}", tag_text, tag_data.cycles());
 
         let surface = font
             .render(&tag_text)
@@ -225,10 +591,16 @@ impl App {
             .create_texture_from_surface(&surface)
             .map_err(|e| e.to_string())?;
 
+        //20 by 50 pixels per character at a ui_scale of 1
+        let target = Rect::new(
+            x,
+            y,
+            (tag_text.len() as f32 * 20.0 * ui_scale) as u32,
+            (50.0 * ui_scale) as u32,
+        );
         canvas.set_draw_color(Color::RGB(255, 255, 255));
-        canvas.fill_rect(Rect::new(x, y, (tag_text.len() * 20) as u32, 50_u32))?;
+        canvas.fill_rect(target)?;
 
-        let target = Rect::new(x, y, (tag_text.len() * 20) as u32, 50_u32);
         canvas.copy(&texture, None, Some(target))?;
 
         Ok(())
@@ -245,23 +617,48 @@ impl App {
             start: 0,
             stop: 0,
         };
-        let mut all_spans_map: HashMap<i32, i32> = HashMap::new();
-        let mut most_recent_spans: Vec<Position> = vec![];
-
-        //getting each y position to be drawn
-        for span in &spans {
-            all_spans_map.insert(self.y_pos(span), -1);
-        }
-        //converting the HashMap to a Vec for increased performance on small numbers of tags
-        for (y, x) in all_spans_map {
-            most_recent_spans.push(Position { x, y });
-        }
+        let mut most_recent_spans = self.rows(&spans);
+        //name being typed for a new view, None when not naming
+        let mut naming: Option<String> = None;
+        //corners of the box being selected with the right mouse button
+        let mut selecting: Option<((i32, i32), (i32, i32))> = None;
+        //corners and description of the last completed selection
+        let mut selection: Option<((i32, i32), (i32, i32), String)> = None;
+        let mut boundaries: Vec<u64> = spans.iter().flat_map(|s| [s.start, s.stop]).collect();
+        boundaries.sort_unstable();
 
         'running: loop {
             let loop_time = Instant::now();
             for event in event_pump.poll_iter() {
-                match event {
+                match self.to_pixels(event) {
                     Event::Quit { .. } => break 'running,
+                    Event::TextInput { text, .. } => {
+                        if let Some(name) = naming.as_mut() {
+                            name.push_str(&text);
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        ..
+                    } if naming.is_some() => match keycode {
+                        Keycode::Return | Keycode::KpEnter => {
+                            self.video_subsystem.text_input().stop();
+                            let name = naming.take().unwrap_or_default();
+                            if !name.is_empty() {
+                                self.save_view(name);
+                            }
+                        }
+                        Keycode::Escape => {
+                            self.video_subsystem.text_input().stop();
+                            naming = None;
+                        }
+                        Keycode::Backspace => {
+                            if let Some(name) = naming.as_mut() {
+                                name.pop();
+                            }
+                        }
+                        _ => {}
+                    },
                     Event::KeyDown {
                         keycode: Some(keycode),
                         ..
@@ -283,14 +680,54 @@ impl App {
                             //reset
                             Keycode::E => {
                                 self.scale =
-                                    (self.max_stop - self.min_start) / (self.window_width as u64)
+                                    self.max_stop.saturating_sub(self.min_start) / (self.window_width as u64)
                             }
                             Keycode::S => self.scroll = self.scroll.saturating_add(keycount), //scroll right
                             Keycode::A => self.scroll = self.scroll.saturating_sub(keycount), //scroll left
                             Keycode::D => self.scroll = 0, //reset
+                            //bookmark the current view under a typed name
+                            Keycode::B => {
+                                naming = Some(String::new());
+                                self.video_subsystem.text_input().start();
+                            }
+                            //toggle coloring by duration instead of tag
+                            Keycode::H => self.heatmap = !self.heatmap,
+                            //toggle rows by nesting depth instead of tag
+                            Keycode::N => {
+                                self.depth_layout = !self.depth_layout;
+                                most_recent_spans = self.rows(&spans);
+                            }
+                            //cycle through saved views
+                            Keycode::V => {
+                                if !self.views.is_empty() {
+                                    let i = self.selected_view.map_or(0, |i| (i + 1) % self.views.len());
+                                    self.select_view(i);
+                                }
+                            }
                             _ => {}
                         }
                     }
+                    Event::MouseButtonDown { mouse_btn: MouseButton::Right, x, y, .. } => {
+                        selecting = Some(((x, y), (x, y)));
+                        selection = None;
+                    }
+                    Event::MouseMotion { x, y, .. } => {
+                        if let Some((_, to)) = selecting.as_mut() {
+                            *to = (x, y);
+                        }
+                    }
+                    Event::MouseButtonUp { mouse_btn: MouseButton::Right, x, y, .. } => {
+                        if let Some((from, _)) = selecting.take() {
+                            let report = self.select(&spans, from, (x, y), &boundaries);
+                            println!("Selection {report}");
+                            selection = Some((from, (x, y), report));
+                        }
+                    }
+                    Event::MouseButtonDown { x, y, .. } if self.sidebar_row(x, y).is_some() => {
+                        if let Some(i) = self.sidebar_row(x, y) {
+                            self.select_view(i);
+                        }
+                    }
                     Event::MouseButtonDown { x, y, .. } => {
                         for zone in &self.draw_zones {
                             if zone.x_start < x
@@ -316,7 +753,7 @@ impl App {
                         win_event: WindowEvent::Resized(w, ..),
                         ..
                     } => {
-                        self.window_width = w as u32;
+                        self.window_width = (w as f32 * self.pixel_ratio) as u32;
                     }
                     _ => {}
                 }
@@ -328,8 +765,12 @@ impl App {
 
             self.canvas.set_draw_color(self.background_color);
             self.canvas.clear();
+            self.draw_grid()?;
             self.draw_zones.clear();
             for span in &spans {
+                if !self.filters.contains(span.tag, span.start) {
+                    continue;
+                }
                 let pos = Position {
                     x: self.x_pos(span),
                     y: self.y_pos(span),
@@ -353,8 +794,24 @@ impl App {
                     draw_x,
                     draw_y,
                     &draw_data,
+                    &self.tag_names,
+                    self.ui_scale,
                 )?;
             }
+            if let Some((from, to)) = selecting {
+                self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+                self.canvas.draw_rect(Rect::new(from.0.min(to.0), from.1.min(to.1), from.0.abs_diff(to.0).max(1), from.1.abs_diff(to.1).max(1)))?;
+            }
+            if let Some((from, to, report)) = &selection {
+                //the box is drawn at its snapped edges
+                let start = self.x_at(self.snap(self.cycle_at(from.0.min(to.0)), &boundaries));
+                let stop = self.x_at(self.snap(self.cycle_at(from.0.max(to.0)), &boundaries));
+                let top = from.1.min(to.1);
+                self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+                self.canvas.draw_rect(Rect::new(start, top, (stop - start).max(1) as u32, from.1.abs_diff(to.1).max(1)))?;
+                Self::draw_label(&mut self.canvas, &self.texture_creator, &font, start, (top - self.sidebar_row_height).max(0), report, Color::RGB(255, 255, 255), (report.len() as f32 * 8.0 * self.ui_scale) as u32, self.ui_scale)?;
+            }
+            self.draw_sidebar(&font, naming.as_ref())?;
 
             self.canvas.present();
 
@@ -374,41 +831,74 @@ impl App {
     }
 }
 
-pub fn load_args(mut args: Vec<String>) -> Vec<Span> {
+pub fn load_args(mut args: Vec<String>) -> (Vec<Span>, Filters) {
     let mut spans = vec![];
+    let filters;
     let config = config::config();
     match args.len() {
         1 => {panic!("Command line arguments were not provided. Format: (file path) (span range start) (span range stop) (tag range start) (tag range stop).")},
         //loading default arguments as long as the file path is provided
         2 => {
             args.push(config.default_args[0].clone());
-            spans = load_args(args);
+            (spans, filters) = load_args(args);
         },
         3 => {
             args.push(config.default_args[1].clone());
-            spans = load_args(args);
+            (spans, filters) = load_args(args);
         },
         4 => {
             args.push(config.default_args[2].clone());
-            spans = load_args(args);
+            (spans, filters) = load_args(args);
         },
         5 => {
             println!("One or more arguments not provided. Running with defaults for missing values.");
             args.push(config.default_args[3].clone());
-            spans = load_args(args);
+            (spans, filters) = load_args(args);
         },
         6 =>{
-            let mut file = File::open(&args[1]).expect("failed to open file");
-            let mut buffer = [0; 24];
+            let mut file = BufReader::new(File::open(&args[1]).expect("failed to open file"));
+            //files written without write_traces_header start directly with the first span
+            //records are tag, start, stop, followed by any fields added by features in the writing build
+            let mut record_width = 3;
+            let mut enter_exit = false;
+            if let Some(header) = TraceHeader::read(&mut file).unwrap_or_else(|e| panic!("failed to read trace header: {e}")) {
+                println!("Trace format version {}", header.version);
+                for (key, value) in &header.entries {
+                    println!("{key}: {value}");
+                }
+                record_width = header.record_width_u64().unwrap_or(3).max(3);
+                enter_exit = header.enter_exit_events();
+                if header.dropped_traces() > 0 {
+                    println!("Warning: the trace array wrapped, the earliest {} spans were overwritten", header.dropped_traces());
+                }
+            }
+            let mut buffer = vec![0; record_width * 8];
             println!("Reading trace file...");
             let span_start = args[2].parse::<u64>().expect("Could not parse span range start");
             let span_stop = args[3].parse::<u64>().expect("Could not parse span range stop");
             let tag_start = args[4].parse::<u64>().expect("Could not parse tag range start");
             let tag_stop = args [5].parse::<u64>().expect("Could not parse tag range stop");
+            filters = Filters { span_start, span_stop, tag_start, tag_stop };
+
+            //enter and exit events only become spans once paired, so read them all before filtering
+            if enter_exit {
+                let mut events = vec![];
+                while file.read_exact(&mut buffer).is_ok() {
+                    let s: Span = bytemuck::pod_read_unaligned(&buffer[..24]);
+                    events.push(Trace { tag: s.tag, start: s.start, stop: s.stop, ..Default::default() });
+                }
+                spans.extend(
+                    pair_events(&events)
+                        .into_iter()
+                        .filter(|t| t.start >= span_start && t.start <= span_stop && t.tag >= tag_start && t.tag <= tag_stop)
+                        .map(|t| Span { tag: t.tag, start: t.start, stop: t.stop }),
+                );
+                return (spans, filters);
+            }
 
             loop{
                 file.read_exact(&mut buffer).expect("failed to fill buffer");
-                let mut s: Span = bytemuck::pod_read_unaligned(&buffer);
+                let mut s: Span = bytemuck::pod_read_unaligned(&buffer[..24]);
                 if s.tag >= tag_start
                     && s.tag <= tag_stop
                 {
@@ -421,7 +911,7 @@ pub fn load_args(mut args: Vec<String>) -> Vec<Span> {
                         }
                         match file.read_exact(&mut buffer) {
                             Ok(..) => {
-                                s = bytemuck::pod_read_unaligned(&buffer);
+                                s = bytemuck::pod_read_unaligned(&buffer[..24]);
                             }
                             Err(..) => {
                                 break;
@@ -434,15 +924,41 @@ pub fn load_args(mut args: Vec<String>) -> Vec<Span> {
         },
         _ => panic!("Command line arguments could not be parsed. Format: (file path) (span range start) (span range stop) (tag range start) (tag range stop)."),
     }
-    spans
+    (spans, filters)
 }
 
 pub fn main() -> Result<(), String> {
-    let mut filled_spans = load_args(env::args().collect::<Vec<String>>());
-    println!("App starting...");
-    let mut app = App::new(&mut filled_spans)?;
-    let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
-    let font_path: &Path = Path::new(&"fonts/Opensans-Regular.ttf");
-    let font = ttf_context.load_font(font_path, 128)?;
-    app.run(filled_spans, font)
+    let args = env::args().collect::<Vec<String>>();
+    //builds without the window viewer always show the terminal one
+    #[cfg(feature = "tui")]
+    if !cfg!(feature = "gui") || args.iter().any(|a| a == "--tui") {
+        let args: Vec<String> = args.into_iter().filter(|a| a != "--tui").collect();
+        //names registered by the traced program, which config.js names take precedence over
+        let tag_names = args
+            .get(1)
+            .and_then(|path| File::open(path).and_then(|f| TraceHeader::read(&mut BufReader::new(f))).ok().flatten())
+            .map(|header| header.tag_names())
+            .unwrap_or_default();
+        let (spans, _) = load_args(args);
+        let traces: Vec<Trace> = spans
+            .iter()
+            .map(|s| Trace { tag: s.tag, start: s.start, stop: s.stop, ..Default::default() })
+            .collect();
+        return tui::run(&traces.into(), tag_names).map_err(|e| e.to_string());
+    }
+    #[cfg(feature = "gui")]
+    {
+        let (mut filled_spans, filters) = load_args(args);
+        println!("App starting...");
+        let mut app = App::new(&mut filled_spans, filters)?;
+        let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
+        let font_path: &Path = Path::new(&"fonts/Opensans-Regular.ttf");
+        let font = ttf_context.load_font(font_path, 128)?;
+        app.run(filled_spans, font)
+    }
+    #[cfg(not(feature = "gui"))]
+    {
+        let _ = args;
+        Err("built without the \"gui\" or \"tui\" feature, enable one of them to view traces".to_string())
+    }
 }
@@ -18,9 +18,35 @@ pub fn config() -> ViewerConfig {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ViewerConfig {
     pub tag_names: Option<HashMap<u64, String>>,
+    /// categories grouping and coloring lanes, overriding those set by the traced program
+    pub tag_categories: Option<HashMap<u64, String>>,
     pub default_args: [String; 4],
     pub window_width: u32,
     pub window_height: u32,
     pub span_height: i32,
     pub span_spacing: i32,
+    /// multiplier of span_height, span_spacing and text sizes, defaults to the monitor's scale factor
+    pub ui_scale: Option<f32>,
+    /// colors used when spans are colored by duration instead of tag, defaults when absent
+    pub heatmap: Option<HeatmapConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HeatmapConfig {
+    /// rgb color of the shortest spans
+    pub cold: (u8, u8, u8),
+    /// rgb color of the longest spans
+    pub hot: (u8, u8, u8),
+    /// scale durations logarithmically, so a few very slow spans don't make the rest look the same
+    pub log_scale: bool,
+}
+
+impl Default for HeatmapConfig {
+    fn default() -> Self {
+        HeatmapConfig {
+            cold: (0, 0, 255),
+            hot: (255, 0, 0),
+            log_scale: true,
+        }
+    }
 }
@@ -0,0 +1,200 @@
+use crate::config;
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::time::Duration;
+use tsc_trace::{TagStats, Trace, Traces};
+
+/// width in characters of the tag name column in front of each lane
+const LABEL_WIDTH: usize = 12;
+
+pub struct Tui {
+    /// traces of each tag, sorted by start, one lane per tag
+    lanes: BTreeMap<u64, Vec<Trace>>,
+    stats: Vec<(u64, TagStats)>,
+    tag_names: HashMap<u64, String>,
+    min_start: u64,
+    max_stop: u64,
+    /// first cycle shown at the left edge of the timeline
+    view_start: u64,
+    /// number of cycles shown across the width of the timeline
+    view_cycles: u64,
+    /// index of the first lane shown
+    lane_scroll: usize,
+}
+
+impl Tui {
+    /// `tag_names` are the names from the dump's header, names from config.js take precedence over them.
+    pub fn new(traces: &Traces, mut tag_names: HashMap<u64, String>) -> Tui {
+        let mut lanes: BTreeMap<u64, Vec<Trace>> = traces
+            .group_by_tag()
+            .into_iter()
+            .map(|(tag, traces)| (tag, traces.into_iter().copied().collect()))
+            .collect();
+        for traces in lanes.values_mut() {
+            traces.sort_unstable_by_key(|t| t.start);
+        }
+        tag_names.extend(config::config().tag_names.unwrap_or_default());
+        //most expensive tags first
+        let stats = traces.tag_stats();
+        let min_start = traces.traces.iter().map(|t| t.start).min().unwrap_or(0);
+        let max_stop = traces.traces.iter().map(|t| t.stop).max().unwrap_or(0);
+        Tui {
+            lanes,
+            stats,
+            tag_names,
+            min_start,
+            max_stop,
+            view_start: min_start,
+            view_cycles: max_stop.saturating_sub(min_start).max(1),
+            lane_scroll: 0,
+        }
+    }
+
+    fn tag_name(&self, tag: u64) -> String {
+        self.tag_names
+            .get(&tag)
+            .cloned()
+            .unwrap_or_else(|| tag.to_string())
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [timeline, table] =
+            Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(frame.area());
+        self.draw_timeline(frame, timeline);
+        self.draw_stats(frame, table);
+    }
+
+    fn draw_timeline(&self, frame: &mut Frame, area: Rect) {
+        let columns = (area.width as usize).saturating_sub(LABEL_WIDTH + 3).max(1);
+        let cycles_per_column = (self.view_cycles / columns as u64).max(1);
+        let view_stop = self.view_start.saturating_add(self.view_cycles);
+        let mut lines = vec![];
+        for (tag, traces) in self.lanes.iter().skip(self.lane_scroll) {
+            let mut cells = vec![' '; columns];
+            for span in traces {
+                if span.start > view_stop {
+                    break;
+                }
+                if span.stop < self.view_start {
+                    continue;
+                }
+                let first = (span.start.saturating_sub(self.view_start) / cycles_per_column) as usize;
+                let last = (span.stop.saturating_sub(self.view_start) / cycles_per_column) as usize;
+                if first >= columns {
+                    continue;
+                }
+                if first == last {
+                    //spans smaller than a column are marked without hiding a full one
+                    if cells[first] == ' ' {
+                        cells[first] = '|';
+                    }
+                } else {
+                    for cell in &mut cells[first..last.min(columns)] {
+                        *cell = '#';
+                    }
+                }
+            }
+            let mut name = self.tag_name(*tag);
+            name.truncate(LABEL_WIDTH);
+            lines.push(Line::from(format!(
+                "{name:>LABEL_WIDTH$} {}",
+                cells.into_iter().collect::<String>()
+            )));
+        }
+        let title = format!(
+            " cycles {}..{} ({} cycles/char) q/w/e zoom, a/s/d move, up/down lanes, esc quit ",
+            self.view_start, view_stop, cycles_per_column
+        );
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)),
+            area,
+        );
+    }
+
+    fn draw_stats(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.stats.iter().map(|(tag, s)| {
+            Row::new(vec![
+                self.tag_name(*tag),
+                s.count.to_string(),
+                s.total.to_string(),
+                s.min.to_string(),
+                s.mean().to_string(),
+                s.max.to_string(),
+            ])
+        });
+        let widths = [
+            Constraint::Length(LABEL_WIDTH as u16),
+            Constraint::Length(10),
+            Constraint::Length(20),
+            Constraint::Length(14),
+            Constraint::Length(14),
+            Constraint::Length(14),
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(vec!["tag", "count", "total", "min", "mean", "max"]))
+            .block(Block::default().borders(Borders::ALL).title(" per tag cycles "));
+        frame.render_widget(table, area);
+    }
+
+    fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(Duration::from_millis(250))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let full = self.max_stop.saturating_sub(self.min_start).max(1);
+            let step = (self.view_cycles / 4).max(1);
+            match key.code {
+                KeyCode::Esc => return Ok(()),
+                //zoom out
+                KeyCode::Char('q') => self.view_cycles = self.view_cycles.saturating_mul(2).min(full),
+                //zoom in
+                KeyCode::Char('w') => self.view_cycles = (self.view_cycles / 2).max(1),
+                //reset
+                KeyCode::Char('e') => self.view_cycles = full,
+                KeyCode::Char('a') => {
+                    self.view_start = self.view_start.saturating_sub(step).max(self.min_start)
+                }
+                KeyCode::Char('s') => {
+                    self.view_start = self.view_start.saturating_add(step).min(self.max_stop)
+                }
+                KeyCode::Char('d') => self.view_start = self.min_start,
+                KeyCode::Up => self.lane_scroll = self.lane_scroll.saturating_sub(1),
+                KeyCode::Down => {
+                    self.lane_scroll = (self.lane_scroll + 1).min(self.lanes.len().saturating_sub(1))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Shows the traces in the terminal until escape is pressed, naming tags by config.js, else by `tag_names` from the header.
+pub fn run(traces: &Traces, tag_names: HashMap<u64, String>) -> io::Result<()> {
+    let mut tui = Tui::new(traces, tag_names);
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let res = tui.event_loop(&mut terminal);
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    res
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A named bookmark of the viewport and the filters it was taken with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct View {
+    pub name: String,
+    pub scale: u64,
+    pub scroll: i32,
+    pub filters: Filters,
+}
+
+/// Span and tag ranges, inclusive, as given on the command line.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Filters {
+    pub span_start: u64,
+    pub span_stop: u64,
+    pub tag_start: u64,
+    pub tag_stop: u64,
+}
+
+impl Filters {
+    pub fn contains(&self, tag: u64, start: u64) -> bool {
+        start >= self.span_start
+            && start <= self.span_stop
+            && tag >= self.tag_start
+            && tag <= self.tag_stop
+    }
+}
+
+/// Views are stored next to the trace file, e.g. my_trace.views.json
+pub fn views_path(trace_path: &str) -> PathBuf {
+    PathBuf::from(format!("{trace_path}.views.json"))
+}
+
+/// Missing files are treated as having no views, so the first save creates one.
+pub fn load_views(path: &Path) -> Vec<View> {
+    match fs::read_to_string(path) {
+        Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+            println!("Ignoring unreadable views file {path:?}: {e}");
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+pub fn save_views(path: &Path, views: &[View]) -> io::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(views)?)
+}